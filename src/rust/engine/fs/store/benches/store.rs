@@ -43,7 +43,10 @@ use protos::gen::build::bazel::remote::execution::v2 as remexec;
 use task_executor::Executor;
 use tempfile::TempDir;
 
-use store::{OneOffStoreFileByDigest, Snapshot, SnapshotOps, Store, SubsetParams};
+use store::{
+  digests_par_to_hashing, OneOffStoreFileByDigest, Snapshot, SnapshotOps, Store, SubsetParams,
+};
+use testutil::data::proto_digests;
 
 pub fn criterion_benchmark_materialize(c: &mut Criterion) {
   // Create an executor, store containing the stuff to materialize, and a digest for the stuff.
@@ -227,12 +230,30 @@ pub fn criterion_benchmark_merge(c: &mut Criterion) {
     });
 }
 
+pub fn criterion_benchmark_digests_to_hashing(c: &mut Criterion) {
+  let executor = Executor::global(num_cpus::get(), num_cpus::get() * 4).unwrap();
+  let digests = proto_digests(50_000);
+
+  let mut cgroup = c.benchmark_group("digests_to_hashing");
+  cgroup.bench_function("sequential", |b| {
+    b.iter(|| protos::digests_to_hashing(&digests).unwrap())
+  });
+  cgroup.bench_function("parallel", |b| {
+    b.iter(|| {
+      executor
+        .block_on(digests_par_to_hashing(&digests, &executor))
+        .unwrap()
+    })
+  });
+}
+
 criterion_group!(
   benches,
   criterion_benchmark_materialize,
   criterion_benchmark_snapshot_capture,
   criterion_benchmark_subset_wildcard,
-  criterion_benchmark_merge
+  criterion_benchmark_merge,
+  criterion_benchmark_digests_to_hashing
 );
 criterion_main!(benches);
 
@@ -211,19 +211,20 @@ impl ByteStore {
   {
     let len = digest.size_bytes;
 
-    let max_batch_total_size_bytes = {
+    let upload_strategy = {
       let capabilities = self.get_capabilities().await?;
 
       capabilities
         .cache_capabilities
         .as_ref()
-        .map(|c| c.max_batch_total_size_bytes as usize)
-        .unwrap_or_default()
+        .map(|c| protos::upload_strategy(c, len))
+        .unwrap_or(protos::UploadStrategy::Batch)
     };
 
+    // NB: Empty (`Inline`) blobs are still uploaded, via the batch API, since not all servers
+    // treat the empty digest as implicitly present.
     let batch_api_allowed_by_local_config = len <= self.batch_api_size_limit;
-    let batch_api_allowed_by_server_config =
-      max_batch_total_size_bytes == 0 || len < max_batch_total_size_bytes;
+    let batch_api_allowed_by_server_config = upload_strategy != protos::UploadStrategy::Stream;
     if batch_api_allowed_by_local_config && batch_api_allowed_by_server_config {
       self.store_bytes_source_batch(digest, bytes).await
     } else {
@@ -5,7 +5,7 @@ use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tempfile::TempDir;
-use testutil::data::{TestData, TestDirectory};
+use testutil::data::{proto_digests, TestData, TestDirectory};
 
 use bytes::{Bytes, BytesMut};
 use fs::{DigestEntry, FileEntry, Permissions};
@@ -15,7 +15,7 @@ use hashing::{Digest, Fingerprint, EMPTY_DIGEST};
 use mock::StubCAS;
 use protos::gen::build::bazel::remote::execution::v2 as remexec;
 
-use crate::{EntryType, FileContent, Store, UploadSummary, MEGABYTES};
+use crate::{digests_par_to_hashing, EntryType, FileContent, Store, UploadSummary, MEGABYTES};
 
 pub(crate) const STORE_BATCH_API_SIZE_LIMIT: usize = 4 * 1024 * 1024;
 
@@ -1506,3 +1506,33 @@ async fn explicitly_overwrites_already_existing_file() {
   let file_contents = std::fs::read(&file_path).unwrap();
   assert_eq!(file_contents, b"abc123".to_vec());
 }
+
+#[tokio::test]
+async fn digests_par_to_hashing_all_valid() {
+  let digests = proto_digests(10_000);
+  let sequential = protos::digests_to_hashing(&digests).unwrap();
+  assert_eq!(sequential.len(), digests.len());
+  assert_eq!(
+    digests_par_to_hashing(&digests, &task_executor::Executor::new()).await,
+    Ok(sequential)
+  );
+}
+
+#[tokio::test]
+async fn digests_par_to_hashing_bad_digest() {
+  let mut digests = proto_digests(10_000);
+  digests[5_000] = remexec::Digest {
+    hash: "0".to_owned(),
+    size_bytes: 1,
+  };
+  let sequential = protos::digests_to_hashing(&digests);
+  assert!(
+    matches!(sequential, Err(ref e) if e.contains("at index 5000")),
+    "Bad result: {:?}",
+    sequential
+  );
+  assert_eq!(
+    digests_par_to_hashing(&digests, &task_executor::Executor::new()).await,
+    sequential
+  );
+}
@@ -516,7 +516,7 @@ pub struct FallibleProcessResultWithPlatform {
 
 /// Metadata for a ProcessResult corresponding to the REAPI `ExecutedActionMetadata` proto. This
 /// conversion is lossy, but the interesting parts are preserved.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ProcessResultMetadata {
   /// The time from starting to completion, including preparing the chroot and cleanup.
   /// Corresponds to `worker_start_timestamp` and `worker_completed_timestamp` from
@@ -529,8 +529,18 @@ pub struct ProcessResultMetadata {
   /// The RunId of the Session in which the `ProcessResultSource` was accurate. In further runs
   /// within the same process, the source of the process implicitly becomes memoization.
   pub source_run_id: RunId,
+  /// The remote worker which ran the process, if it was reported. Corresponds to `worker` from
+  /// `ExecutedActionMetadata`.
+  pub worker: Option<String>,
+  /// Vendor-specific metadata attached by the server which ran the process, in order. Corresponds
+  /// to `auxiliary_metadata` from `ExecutedActionMetadata`.
+  pub auxiliary_metadata: Vec<prost_types::Any>,
 }
 
+// NB: `prost_types::Any` does not implement `Eq`, although its `PartialEq` implementation (which
+// compares only strings and bytes) is an equivalence relation.
+impl Eq for ProcessResultMetadata {}
+
 impl ProcessResultMetadata {
   pub fn new(
     total_elapsed: Option<Duration>,
@@ -541,6 +551,8 @@ impl ProcessResultMetadata {
       total_elapsed,
       source,
       source_run_id,
+      worker: None,
+      auxiliary_metadata: vec![],
     }
   }
 
@@ -558,10 +570,17 @@ impl ProcessResultMetadata {
         .ok(),
       _ => None,
     };
+    let worker = if metadata.worker.is_empty() {
+      None
+    } else {
+      Some(metadata.worker)
+    };
     Self {
       total_elapsed,
       source,
       source_run_id,
+      worker,
+      auxiliary_metadata: metadata.auxiliary_metadata,
     }
   }
 
@@ -609,8 +628,10 @@ impl From<ProcessResultMetadata> for ExecutedActionMetadata {
       None => (None, None),
     };
     ExecutedActionMetadata {
+      worker: metadata.worker.unwrap_or_default(),
       worker_start_timestamp: total_start,
       worker_completed_timestamp: total_end,
+      auxiliary_metadata: metadata.auxiliary_metadata,
       ..ExecutedActionMetadata::default()
     }
   }
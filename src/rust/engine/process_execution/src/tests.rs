@@ -103,6 +103,73 @@ fn process_result_metadata_to_and_from_executed_action_metadata() {
   assert_eq!(process_result_missing, ExecutedActionMetadata::default());
 }
 
+#[test]
+fn process_result_metadata_preserves_worker() {
+  let action_metadata = ExecutedActionMetadata {
+    worker: "worker-17".to_owned(),
+    worker_start_timestamp: Some(Timestamp {
+      seconds: 100,
+      nanos: 0,
+    }),
+    worker_completed_timestamp: Some(Timestamp {
+      seconds: 105,
+      nanos: 0,
+    }),
+    ..ExecutedActionMetadata::default()
+  };
+
+  let converted_process_result = ProcessResultMetadata::new_from_metadata(
+    action_metadata,
+    ProcessResultSource::RanRemotely,
+    RunId(0),
+  );
+  assert_eq!(
+    converted_process_result.worker,
+    Some("worker-17".to_owned())
+  );
+  assert_eq!(
+    converted_process_result.total_elapsed,
+    Some(concrete_time::Duration::new(5, 0))
+  );
+
+  let restored_action_metadata: ExecutedActionMetadata = converted_process_result.into();
+  assert_eq!(restored_action_metadata.worker, "worker-17");
+}
+
+#[test]
+fn process_result_metadata_preserves_auxiliary_metadata() {
+  let auxiliary_metadata = vec![
+    prost_types::Any {
+      type_url: "type.example.com/vendor.WorkerStats".to_owned(),
+      value: vec![1, 2, 3],
+    },
+    prost_types::Any {
+      type_url: "type.example.com/vendor.QueueStats".to_owned(),
+      value: vec![4, 5],
+    },
+  ];
+  let action_metadata = ExecutedActionMetadata {
+    auxiliary_metadata: auxiliary_metadata.clone(),
+    ..ExecutedActionMetadata::default()
+  };
+
+  let converted_process_result = ProcessResultMetadata::new_from_metadata(
+    action_metadata,
+    ProcessResultSource::RanRemotely,
+    RunId(0),
+  );
+  assert_eq!(
+    converted_process_result.auxiliary_metadata,
+    auxiliary_metadata
+  );
+
+  let restored_action_metadata: ExecutedActionMetadata = converted_process_result.into();
+  assert_eq!(
+    restored_action_metadata.auxiliary_metadata,
+    auxiliary_metadata
+  );
+}
+
 #[test]
 fn process_result_metadata_time_saved_from_cache() {
   let metadata = ProcessResultMetadata::new(
@@ -1,5 +1,14 @@
+use std::collections::HashSet;
 use std::convert::TryFrom;
 
+use prost::Message;
+
+use crate::gen::build::bazel::remote::execution::v2 as remexec;
+use crate::gen::build::bazel::semver::SemVer;
+use crate::gen::google::bytestream;
+use crate::gen::google::longrunning::{operation, Operation};
+use crate::gen::google::rpc;
+
 impl<'a> From<&'a hashing::Digest> for crate::gen::build::bazel::remote::execution::v2::Digest {
   fn from(d: &'a hashing::Digest) -> Self {
     Self {
@@ -24,9 +33,8 @@ impl<'a> TryFrom<&'a crate::gen::build::bazel::remote::execution::v2::Digest> fo
   fn try_from(
     d: &crate::gen::build::bazel::remote::execution::v2::Digest,
   ) -> Result<Self, Self::Error> {
-    hashing::Fingerprint::from_hex_string(&d.hash)
-      .map_err(|err| format!("Bad fingerprint in Digest {:?}: {:?}", &d.hash, err))
-      .map(|fingerprint| hashing::Digest::new(fingerprint, d.size_bytes as usize))
+    let fingerprint = digest_fingerprint(d)?;
+    Ok(hashing::Digest::new(fingerprint, digest_size_bytes(d)?))
   }
 }
 
@@ -36,10 +44,55 @@ impl TryFrom<crate::gen::build::bazel::remote::execution::v2::Digest> for hashin
   fn try_from(
     d: crate::gen::build::bazel::remote::execution::v2::Digest,
   ) -> Result<Self, Self::Error> {
-    hashing::Fingerprint::from_hex_string(&d.hash)
-      .map_err(|err| format!("Bad fingerprint in Digest {:?}: {:?}", &d.hash, err))
-      .map(|fingerprint| hashing::Digest::new(fingerprint, d.size_bytes as usize))
+    hashing::Digest::try_from(&d)
+  }
+}
+
+///
+/// Parses the hash of the given Digest, which may use either upper or lower case hex.
+///
+fn digest_fingerprint(
+  d: &crate::gen::build::bazel::remote::execution::v2::Digest,
+) -> Result<hashing::Fingerprint, String> {
+  if let Some(c) = d.hash.chars().find(|c| !c.is_ascii_hexdigit()) {
+    return Err(format!(
+      "Bad fingerprint in Digest {:?}: not hexadecimal: contains {:?}",
+      &d.hash, c
+    ));
+  }
+  if d.hash.len() != hashing::FINGERPRINT_SIZE * 2 {
+    return Err(format!(
+      "Bad fingerprint in Digest {:?}: wrong length: expected {} hex characters, got {}",
+      &d.hash,
+      hashing::FINGERPRINT_SIZE * 2,
+      d.hash.len()
+    ));
   }
+  hashing::Fingerprint::from_hex_string(&d.hash.to_ascii_lowercase())
+    .map_err(|err| format!("Bad fingerprint in Digest {:?}: {}", &d.hash, err))
+}
+
+///
+/// Validates the size of the given Digest, which must be non-negative and must fit in a `usize` on
+/// this platform (which, on 32-bit platforms, excludes blobs of 4GB and larger).
+///
+fn digest_size_bytes(
+  d: &crate::gen::build::bazel::remote::execution::v2::Digest,
+) -> Result<usize, String> {
+  if d.size_bytes < 0 {
+    return Err(format!(
+      "Negative size_bytes in Digest {:?}: {}",
+      &d.hash, d.size_bytes
+    ));
+  }
+  usize::try_from(d.size_bytes).map_err(|_| {
+    format!(
+      "size_bytes in Digest {:?} does not fit in a {}-bit usize: {}",
+      &d.hash,
+      usize::BITS,
+      d.size_bytes
+    )
+  })
 }
 
 pub fn require_digest<
@@ -53,3 +106,1018 @@ pub fn require_digest<
     None => Err("Protocol violation: Digest missing from a Remote Execution API protobuf.".into()),
   }
 }
+
+///
+/// Converts the digest of a Snapshot, which is always the digest of its root `Directory` (and never
+/// of a file), for use in fields which expect a `Directory` digest (such as
+/// `Action.input_root_digest`).
+///
+/// NB: This is identical to the generic Digest conversion, but makes it explicit at call sites
+/// that the digest identifies a Directory rather than file content.
+///
+pub fn snapshot_root_to_directory_digest(digest: &hashing::Digest) -> remexec::Digest {
+  digest.into()
+}
+
+///
+/// Converts a digest for reference from an emitted build event. Event digests currently have the
+/// same shape as CAS digests: this is the single place to adjust if they ever diverge.
+///
+pub fn digest_to_event_digest(digest: &hashing::Digest) -> remexec::Digest {
+  digest.into()
+}
+
+///
+/// Parses a digest referenced by an ingested build event, validating its fingerprint and size: the
+/// reverse of `digest_to_event_digest`.
+///
+pub fn event_digest_to_digest(digest: &remexec::Digest) -> Result<hashing::Digest, String> {
+  hashing::Digest::try_from(digest)
+    .map_err(|e| format!("Bad digest referenced by build event: {}", e))
+}
+
+///
+/// A `google.longrunning.Operation` received while following a remote execution, along with the
+/// progress information decoded from its `ExecuteOperationMetadata` (if any).
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct OperationUpdate {
+  pub operation: Operation,
+  pub stage: Option<remexec::execution_stage::Value>,
+  pub action_digest: Option<hashing::Digest>,
+}
+
+const EXECUTE_OPERATION_METADATA_TYPE_URL: &str =
+  "type.googleapis.com/build.bazel.remote.execution.v2.ExecuteOperationMetadata";
+
+///
+/// Decodes the `ExecuteOperationMetadata` of the given Operation (if its metadata is of that type),
+/// so that callers can report progress without re-decoding the `Any` themselves.
+///
+pub fn operation_update(operation: Operation) -> Result<OperationUpdate, String> {
+  let metadata = match operation.metadata {
+    Some(ref any) if any.type_url == EXECUTE_OPERATION_METADATA_TYPE_URL => Some(
+      remexec::ExecuteOperationMetadata::decode(&any.value[..]).map_err(|e| {
+        format!(
+          "Invalid ExecuteOperationMetadata in Operation {:?}: {:?}",
+          operation.name, e
+        )
+      })?,
+    ),
+    _ => None,
+  };
+
+  let (stage, action_digest) = if let Some(metadata) = metadata {
+    let action_digest = metadata
+      .action_digest
+      .as_ref()
+      .map(hashing::Digest::try_from)
+      .transpose()?;
+    (
+      remexec::execution_stage::Value::from_i32(metadata.stage),
+      action_digest,
+    )
+  } else {
+    (None, None)
+  };
+
+  Ok(OperationUpdate {
+    operation,
+    stage,
+    action_digest,
+  })
+}
+
+///
+/// The state of a remote execution, as reported by an `OperationUpdate`: see
+/// `OperationUpdate::outcome`.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum OperationOutcome {
+  /// The Operation is not done yet (as when reconnecting via `WaitExecution`), and reports only its
+  /// current stage (if any).
+  Pending(Option<remexec::execution_stage::Value>),
+  /// The Operation is done, but failed with the given error: either the error of the Operation, or
+  /// the non-OK status of its ExecuteResponse.
+  Failed(rpc::Status),
+  /// The Operation is done, and succeeded with the given ExecuteResponse.
+  Completed(remexec::ExecuteResponse),
+}
+
+impl OperationUpdate {
+  ///
+  /// Distinguishes an Operation which is still in progress from one which has failed or completed.
+  /// An Operation which is not yet done is expected not to have a result: that is not an error.
+  ///
+  /// Fails if a done Operation has no result, or has a response which cannot be decoded.
+  ///
+  pub fn outcome(&self) -> Result<OperationOutcome, String> {
+    if !self.operation.done {
+      return Ok(OperationOutcome::Pending(self.stage));
+    }
+    match self.operation.result {
+      Some(operation::Result::Error(ref status)) => Ok(OperationOutcome::Failed(status.clone())),
+      Some(operation::Result::Response(ref any)) => {
+        let response = remexec::ExecuteResponse::decode(&any.value[..]).map_err(|e| {
+          format!(
+            "Invalid ExecuteResponse in Operation {:?}: {:?}",
+            self.operation.name, e
+          )
+        })?;
+        match response.status {
+          Some(ref status) if status.code != tonic::Code::Ok as i32 => {
+            Ok(OperationOutcome::Failed(status.clone()))
+          }
+          _ => Ok(OperationOutcome::Completed(response)),
+        }
+      }
+      None => Err(format!(
+        "Operation {:?} was done, but had no result.",
+        self.operation.name
+      )),
+    }
+  }
+}
+
+impl remexec::Directory {
+  ///
+  /// Returns a copy of this Directory without any of its symlinks, for use with servers (or
+  /// materialization strategies) which do not support them.
+  ///
+  pub fn strip_symlinks(&self) -> remexec::Directory {
+    remexec::Directory {
+      symlinks: vec![],
+      ..self.clone()
+    }
+  }
+}
+
+// The permission bits of a UNIX file mode which are honored when materializing files. The
+// setuid, setgid and sticky bits (0o7000) are deliberately not honored: although they fit in the
+// 0o7777 range of a mode, materializing a setuid or setgid file from a remote Tree would allow
+// that Tree to escalate privileges, and the sticky bit has no meaning for our files.
+const HONORED_UNIX_MODE_BITS: u32 = 0o777;
+
+///
+/// Masks the given UNIX file mode to the permission bits that we honor, warning if any other bits
+/// (such as setuid, setgid or sticky) were present and stripped.
+///
+pub fn sanitize_unix_mode(mode: u32) -> u32 {
+  let sanitized = mode & HONORED_UNIX_MODE_BITS;
+  if sanitized != mode {
+    log::warn!(
+      "Ignoring unsupported bits of UNIX file mode {:#o}: using {:#o}.",
+      mode,
+      sanitized
+    );
+  }
+  sanitized
+}
+
+impl remexec::NodeProperties {
+  ///
+  /// Returns the `unix_mode` of these NodeProperties (if set), sanitized by `sanitize_unix_mode`.
+  ///
+  pub fn sanitized_unix_mode(&self) -> Option<u32> {
+    self.unix_mode.map(sanitize_unix_mode)
+  }
+}
+
+impl remexec::Tree {
+  ///
+  /// Validates that the children of this Tree are in topological order: that each child Directory
+  /// is referenced by the root or by a child which precedes it. A Tree in this order may be
+  /// materialized in a single pass.
+  ///
+  /// Fails with the digest of the first child which is not referenced by a preceding Directory.
+  ///
+  pub fn validate_topological_order(&self) -> Result<(), String> {
+    fn add_references(
+      referenced: &mut HashSet<hashing::Digest>,
+      directory: &remexec::Directory,
+    ) -> Result<(), String> {
+      for subdirectory in &directory.directories {
+        referenced.insert(require_digest(subdirectory.digest.as_ref()).map_err(|e| {
+          format!(
+            "Bad digest for directory {:?} in Tree: {}",
+            subdirectory.name, e
+          )
+        })?);
+      }
+      Ok(())
+    }
+
+    let mut referenced = HashSet::new();
+    if let Some(root) = &self.root {
+      add_references(&mut referenced, root)?;
+    }
+    for child in &self.children {
+      let digest = hashing::Digest::of_bytes(&child.encode_to_vec());
+      if !referenced.contains(&digest) {
+        return Err(format!(
+          "Tree is not topologically sorted: child {:?} is not referenced by any preceding \
+           directory.",
+          digest
+        ));
+      }
+      add_references(&mut referenced, child)?;
+    }
+    Ok(())
+  }
+}
+
+impl remexec::ActionResult {
+  ///
+  /// Moves all entries from the (deprecated) `output_file_symlinks` and `output_directory_symlinks`
+  /// fields into the combined `output_symlinks` field, for use with servers which only consume the
+  /// combined field.
+  ///
+  /// Because servers are encouraged to populate both forms, entries which are already present in
+  /// `output_symlinks` are not duplicated.
+  ///
+  pub fn merge_symlink_fields(&mut self) {
+    let split_symlinks = std::mem::take(&mut self.output_file_symlinks)
+      .into_iter()
+      .chain(std::mem::take(&mut self.output_directory_symlinks))
+      .collect::<Vec<_>>();
+    for symlink in split_symlinks {
+      if !self.output_symlinks.contains(&symlink) {
+        self.output_symlinks.push(symlink);
+      }
+    }
+  }
+}
+
+impl remexec::Command {
+  ///
+  /// Sets the outputs of this Command. If `use_output_paths` is set (for servers supporting REAPI
+  /// v2.1 or newer), the files and directories are combined into the sorted `output_paths` field.
+  /// Otherwise, the deprecated split `output_files` and `output_directories` fields are populated.
+  ///
+  pub fn set_outputs(
+    &mut self,
+    mut output_files: Vec<String>,
+    mut output_directories: Vec<String>,
+    use_output_paths: bool,
+  ) {
+    if use_output_paths {
+      let mut output_paths = output_files
+        .into_iter()
+        .chain(output_directories)
+        .collect::<Vec<_>>();
+      output_paths.sort();
+      output_paths.dedup();
+      self.output_paths = output_paths;
+      self.output_files = vec![];
+      self.output_directories = vec![];
+    } else {
+      output_files.sort();
+      output_directories.sort();
+      self.output_paths = vec![];
+      self.output_files = output_files;
+      self.output_directories = output_directories;
+    }
+  }
+
+  ///
+  /// Returns the outputs of this Command as `(output_files, output_directories)`, merging in any
+  /// entries from the unified `output_paths` field.
+  ///
+  /// Because `output_paths` does not distinguish files from directories, its entries are treated as
+  /// files unless they are also listed in `output_directories`.
+  ///
+  pub fn split_outputs(&self) -> (Vec<String>, Vec<String>) {
+    let mut output_files = self.output_files.clone();
+    for path in &self.output_paths {
+      if !output_files.contains(path) && !self.output_directories.contains(path) {
+        output_files.push(path.clone());
+      }
+    }
+    output_files.sort();
+    let mut output_directories = self.output_directories.clone();
+    output_directories.sort();
+    (output_files, output_directories)
+  }
+}
+
+///
+/// Creates a BatchReadBlobsRequest for the given digests, in order.
+///
+pub fn batch_read_request(
+  instance_name: String,
+  digests: &[hashing::Digest],
+) -> remexec::BatchReadBlobsRequest {
+  remexec::BatchReadBlobsRequest {
+    instance_name,
+    digests: digests.iter().map(|d| d.into()).collect(),
+  }
+}
+
+///
+/// The outcome of reading a single blob in a BatchReadBlobs request: see
+/// `parse_batch_read_response`.
+///
+pub type BatchReadEntry = Result<(hashing::Digest, bytes::Bytes), (hashing::Digest, String)>;
+
+///
+/// Converts each entry of a BatchReadBlobsResponse into either the digest and content of a blob
+/// which was read successfully, or the digest and status message of a blob which could not be read.
+///
+/// Fails only if an entry does not have a valid digest.
+///
+pub fn parse_batch_read_response(
+  response: remexec::BatchReadBlobsResponse,
+) -> Result<Vec<BatchReadEntry>, String> {
+  response
+    .responses
+    .into_iter()
+    .enumerate()
+    .map(|(i, entry)| {
+      let digest = require_digest(entry.digest.as_ref()).map_err(|e| {
+        format!(
+          "Bad digest for entry {} of BatchReadBlobsResponse: {}",
+          i, e
+        )
+      })?;
+      match status_result(entry.status) {
+        Ok(()) => Ok(Ok((digest, entry.data))),
+        Err(e) => Ok(Err((digest, e))),
+      }
+    })
+    .collect()
+}
+
+///
+/// Converts each entry of a BatchUpdateBlobsResponse into the digest of the blob and whether it was
+/// uploaded successfully, so that callers may retry only the failed blobs.
+///
+/// Fails only if an entry does not have a valid digest.
+///
+pub fn parse_batch_update_response(
+  response: remexec::BatchUpdateBlobsResponse,
+) -> Result<Vec<(hashing::Digest, Result<(), String>)>, String> {
+  response
+    .responses
+    .into_iter()
+    .enumerate()
+    .map(|(i, entry)| {
+      let digest = require_digest(entry.digest.as_ref()).map_err(|e| {
+        format!(
+          "Bad digest for entry {} of BatchUpdateBlobsResponse: {}",
+          i, e
+        )
+      })?;
+      Ok((digest, status_result(entry.status)))
+    })
+    .collect()
+}
+
+///
+/// Formats the failure reported by an ExecuteResponse (combining the code and message of its
+/// `status` with its own `message`), or returns None if the execution succeeded.
+///
+/// NB: The engine's `Failure` type wraps a Python exception, which this crate cannot construct:
+/// callers in the engine should wrap the message with `throw`, so that remote failures are
+/// surfaced in the same way as local failures.
+///
+pub fn execute_response_failure_message(response: &remexec::ExecuteResponse) -> Option<String> {
+  let status = response.status.as_ref()?;
+  if status.code == tonic::Code::Ok as i32 {
+    return None;
+  }
+  let mut message = format!(
+    "Remote execution failed with {:?}: {}",
+    tonic::Code::from_i32(status.code),
+    status.message
+  );
+  if !response.message.is_empty() {
+    message.push_str(&format!("\n{}", response.message));
+  }
+  Some(message)
+}
+
+///
+/// The canonical name of the binary header which carries a `RequestMetadata` message.
+///
+pub const REQUEST_METADATA_HEADER: &str = "build.bazel.remote.execution.v2.requestmetadata-bin";
+
+///
+/// Returns the canonical header name and encoded value for the given RequestMetadata, which the
+/// transport layer will base64 encode (as for any binary header).
+///
+pub fn request_metadata_to_header(metadata: &remexec::RequestMetadata) -> (String, Vec<u8>) {
+  (REQUEST_METADATA_HEADER.to_owned(), metadata.encode_to_vec())
+}
+
+///
+/// Decodes the (already base64 decoded) value of a `REQUEST_METADATA_HEADER` header: the reverse of
+/// `request_metadata_to_header`.
+///
+pub fn request_metadata_from_header(value: &[u8]) -> Result<remexec::RequestMetadata, String> {
+  remexec::RequestMetadata::decode(value)
+    .map_err(|e| format!("Invalid RequestMetadata header: {:?}", e))
+}
+
+///
+/// Converts the Status of an entry in a batch response into a Result, treating a missing Status as
+/// OK.
+///
+fn status_result(status: Option<rpc::Status>) -> Result<(), String> {
+  let status = status.unwrap_or_default();
+  if status.code == tonic::Code::Ok as i32 {
+    Ok(())
+  } else {
+    Err(format!(
+      "{:?}: {}",
+      tonic::Code::from_i32(status.code),
+      status.message
+    ))
+  }
+}
+
+///
+/// Creates a FindMissingBlobsRequest for the given digests, in order and without duplicates.
+///
+pub fn find_missing_request(
+  instance_name: String,
+  digests: &[hashing::Digest],
+) -> remexec::FindMissingBlobsRequest {
+  remexec::FindMissingBlobsRequest {
+    instance_name,
+    blob_digests: dedup_digests_to_prost(digests),
+  }
+}
+
+///
+/// Converts the given digests, skipping any which have already been seen. The first occurrence of
+/// each digest is kept in its original position, so that the output is deterministic.
+///
+pub fn dedup_digests_to_prost(digests: &[hashing::Digest]) -> Vec<remexec::Digest> {
+  let mut seen = HashSet::with_capacity(digests.len());
+  digests
+    .iter()
+    .filter(|digest| seen.insert(**digest))
+    .map(|digest| digest.into())
+    .collect()
+}
+
+///
+/// Converts the given digests, reporting the index of the first bad digest.
+///
+pub fn digests_to_hashing(digests: &[remexec::Digest]) -> Result<Vec<hashing::Digest>, String> {
+  digests_to_hashing_from(digests, 0)
+}
+
+///
+/// Converts the given digests, which begin at `first_index` of some larger list (as when
+/// converting it in chunks), reporting the index in that list of the first bad digest.
+///
+pub fn digests_to_hashing_from(
+  digests: &[remexec::Digest],
+  first_index: usize,
+) -> Result<Vec<hashing::Digest>, String> {
+  digests
+    .iter()
+    .enumerate()
+    .map(|(i, digest)| {
+      hashing::Digest::try_from(digest)
+        .map_err(|e| format!("Bad digest at index {}: {}", first_index + i, e))
+    })
+    .collect()
+}
+
+///
+/// Returns the digests which a FindMissingBlobsResponse reported as missing.
+///
+pub fn find_missing_response_digests(
+  response: &remexec::FindMissingBlobsResponse,
+) -> Result<Vec<hashing::Digest>, String> {
+  response
+    .missing_blob_digests
+    .iter()
+    .enumerate()
+    .map(|(i, digest)| {
+      hashing::Digest::try_from(digest).map_err(|e| {
+        format!(
+          "Bad digest at index {} of FindMissingBlobsResponse: {}",
+          i, e
+        )
+      })
+    })
+    .collect()
+}
+
+///
+/// Creates a ByteStream WriteRequest for one chunk of an upload to the given resource.
+///
+pub fn write_request(
+  resource_name: String,
+  write_offset: i64,
+  data: bytes::Bytes,
+  finish_write: bool,
+) -> bytestream::WriteRequest {
+  bytestream::WriteRequest {
+    resource_name,
+    write_offset,
+    finish_write,
+    data,
+  }
+}
+
+///
+/// Creates a ByteStream ReadRequest for the given resource. A `read_limit` of zero indicates that
+/// there is no limit.
+///
+pub fn read_request(
+  resource_name: String,
+  read_offset: i64,
+  read_limit: i64,
+) -> bytestream::ReadRequest {
+  bytestream::ReadRequest {
+    resource_name,
+    read_offset,
+    read_limit,
+  }
+}
+
+///
+/// The lowercase name used for a DigestFunction in ByteStream resource names, or None for
+/// `Unknown`.
+///
+fn digest_function_segment(
+  digest_function: remexec::digest_function::Value,
+) -> Option<&'static str> {
+  use remexec::digest_function::Value;
+  match digest_function {
+    Value::Unknown => None,
+    Value::Sha256 => Some("sha256"),
+    Value::Sha1 => Some("sha1"),
+    Value::Md5 => Some("md5"),
+    Value::Vso => Some("vso"),
+    Value::Sha384 => Some("sha384"),
+    Value::Sha512 => Some("sha512"),
+    Value::Murmur3 => Some("murmur3"),
+  }
+}
+
+fn digest_function_from_segment(segment: &str) -> Option<remexec::digest_function::Value> {
+  use remexec::digest_function::Value;
+  [
+    Value::Sha256,
+    Value::Sha1,
+    Value::Md5,
+    Value::Vso,
+    Value::Sha384,
+    Value::Sha512,
+    Value::Murmur3,
+  ]
+  .into_iter()
+  .find(|value| digest_function_segment(*value) == Some(segment))
+}
+
+///
+/// Formats the ByteStream resource name used to read the given Digest from the CAS:
+/// `{instance_name}/blobs/[{digest_function}/]{hash}/{size}`, without the leading
+/// `{instance_name}/` if there is no instance name.
+///
+/// The digest function segment is only included if one is given (and it is not `Unknown`): servers
+/// which predate it only accept the legacy layout.
+///
+pub fn digest_to_resource_name(
+  instance_name: &str,
+  digest: &hashing::Digest,
+  digest_function: Option<remexec::digest_function::Value>,
+) -> String {
+  let blob = match digest_function.and_then(digest_function_segment) {
+    Some(segment) => format!("blobs/{}/{}/{}", segment, digest.hash, digest.size_bytes),
+    None => format!("blobs/{}/{}", digest.hash, digest.size_bytes),
+  };
+  if instance_name.is_empty() {
+    blob
+  } else {
+    format!("{}/{}", instance_name, blob)
+  }
+}
+
+///
+/// Extracts the Digest embedded in a ByteStream resource name for a CAS blob. See
+/// `resource_name_digest_and_function`.
+///
+pub fn resource_name_digest(resource_name: &str) -> Result<hashing::Digest, String> {
+  resource_name_digest_and_function(resource_name).map(|(digest, _)| digest)
+}
+
+///
+/// Extracts the Digest and DigestFunction embedded in a ByteStream resource name for a CAS blob,
+/// which has the form `[{instance_name}/][uploads/{uuid}/]blobs/[{digest_function}/]{hash}/{size}`
+/// (optionally followed by `/{optional_metadata}`).
+///
+/// When the digest function segment is absent, the function defaults to SHA256.
+///
+pub fn resource_name_digest_and_function(
+  resource_name: &str,
+) -> Result<(hashing::Digest, remexec::digest_function::Value), String> {
+  let components = resource_name.split('/').collect::<Vec<_>>();
+  let malformed = || {
+    format!(
+      "Malformed resource name {:?}: expected `.../blobs/[{{digest_function}}/]{{hash}}/{{size}}`",
+      resource_name
+    )
+  };
+  let blobs = components
+    .iter()
+    .position(|component| *component == "blobs")
+    .ok_or_else(malformed)?;
+  let rest = &components[blobs + 1..];
+
+  // A legacy resource name has a hash immediately after `blobs`, which can never collide with the
+  // (non-hex) name of a digest function.
+  let (digest_function, rest) = match rest.first() {
+    Some(segment) if hashing::Fingerprint::from_hex_string(segment).is_ok() => {
+      (remexec::digest_function::Value::Sha256, rest)
+    }
+    Some(segment) => match digest_function_from_segment(segment) {
+      Some(digest_function) => (digest_function, &rest[1..]),
+      None if rest.len() >= 3 && hashing::Fingerprint::from_hex_string(rest[1]).is_ok() => {
+        return Err(format!(
+          "Unsupported digest function {:?} in resource name {:?}",
+          segment, resource_name
+        ));
+      }
+      None => (remexec::digest_function::Value::Sha256, rest),
+    },
+    None => return Err(malformed()),
+  };
+
+  let (hash, size) = match rest {
+    [hash, size, ..] => (hash, size),
+    _ => return Err(malformed()),
+  };
+  let fingerprint = hashing::Fingerprint::from_hex_string(hash)
+    .map_err(|e| format!("Bad hash in resource name {:?}: {}", resource_name, e))?;
+  let size_bytes = size
+    .parse::<usize>()
+    .map_err(|e| format!("Bad size in resource name {:?}: {}", resource_name, e))?;
+  Ok((
+    hashing::Digest::new(fingerprint, size_bytes),
+    digest_function,
+  ))
+}
+
+///
+/// Extracts an Action digest embedded in an Operation name as `.../actions/{hash}/{size}`, which
+/// some (but not all) servers use when naming Operations.
+///
+/// Because the format of Operation names is server-specific, this is best-effort: names which do
+/// not embed a valid digest result in None.
+///
+pub fn parse_operation_name_digest(name: &str) -> Option<hashing::Digest> {
+  let components = name.split('/').collect::<Vec<_>>();
+  components
+    .windows(3)
+    .filter(|window| window[0] == "actions")
+    .find_map(|window| {
+      let fingerprint = hashing::Fingerprint::from_hex_string(window[1]).ok()?;
+      let size_bytes = window[2].parse::<usize>().ok()?;
+      Some(hashing::Digest::new(fingerprint, size_bytes))
+    })
+}
+
+///
+/// Decodes the violations from all `PreconditionFailure` details of the given Status, which (for a
+/// `FAILED_PRECONDITION` status from remote execution) describe the blobs which must be uploaded
+/// before retrying.
+///
+/// Details of any other type are ignored, as are (with a warning) details which fail to decode.
+///
+pub fn decode_precondition_failure(
+  status: &rpc::Status,
+) -> Vec<rpc::precondition_failure::Violation> {
+  status
+    .details
+    .iter()
+    .filter(|details| details.type_url == "type.googleapis.com/google.rpc.PreconditionFailure")
+    .flat_map(
+      |details| match rpc::PreconditionFailure::decode(&details.value[..]) {
+        Ok(precondition_failure) => precondition_failure.violations,
+        Err(e) => {
+          log::warn!("Error deserializing PreconditionFailure proto: {:?}", e);
+          vec![]
+        }
+      },
+    )
+    .collect()
+}
+
+///
+/// Returns the digests of all files referenced by the given Tree (in its root and all of its
+/// children), e.g. in order to prefetch them before materialization.
+///
+pub fn tree_referenced_digests(tree: &remexec::Tree) -> Result<Vec<hashing::Digest>, String> {
+  let directories = tree
+    .root
+    .iter()
+    .map(|root| ("root".to_owned(), root))
+    .chain(
+      tree
+        .children
+        .iter()
+        .enumerate()
+        .map(|(i, child)| (format!("child {}", i), child)),
+    );
+
+  let mut digests = Vec::new();
+  for (directory_name, directory) in directories {
+    for file in &directory.files {
+      let digest = require_digest(file.digest.as_ref()).map_err(|e| {
+        format!(
+          "Bad digest for file {:?} in {} directory of Tree: {}",
+          file.name, directory_name, e
+        )
+      })?;
+      digests.push(digest);
+    }
+  }
+  Ok(digests)
+}
+
+///
+/// Converts the raw value of a `DigestFunction.Value` field, mapping unrecognized values to
+/// `Unknown` (with a warning) rather than failing.
+///
+pub fn digest_function_from_i32(value: i32) -> remexec::digest_function::Value {
+  remexec::digest_function::Value::from_i32(value).unwrap_or_else(|| {
+    log::warn!("Unrecognized DigestFunction value: {}", value);
+    remexec::digest_function::Value::Unknown
+  })
+}
+
+///
+/// Converts a protobuf Duration (e.g. `Action.timeout`) to a std Duration, saturating negative
+/// durations to zero.
+///
+/// The reverse conversion is provided by `prost_types::Duration::from(std::time::Duration)`.
+///
+pub fn std_duration_from_prost(duration: &prost_types::Duration) -> std::time::Duration {
+  let total_nanos = i128::from(duration.seconds) * 1_000_000_000 + i128::from(duration.nanos);
+  if total_nanos <= 0 {
+    return std::time::Duration::ZERO;
+  }
+  std::time::Duration::new(
+    (total_nanos / 1_000_000_000) as u64,
+    (total_nanos % 1_000_000_000) as u32,
+  )
+}
+
+///
+/// Converts the raw value of an `ExecutionStage.Value` field, mapping unrecognized values to
+/// `Unknown` (with a warning) rather than failing.
+///
+pub fn execution_stage_from_i32(value: i32) -> remexec::execution_stage::Value {
+  remexec::execution_stage::Value::from_i32(value).unwrap_or_else(|| {
+    log::warn!("Unrecognized ExecutionStage value: {}", value);
+    remexec::execution_stage::Value::Unknown
+  })
+}
+
+///
+/// A user-facing label for the given stage of remote execution, for use in logs.
+///
+pub fn stage_label(stage: remexec::execution_stage::Value) -> &'static str {
+  match stage {
+    remexec::execution_stage::Value::Unknown => "unknown",
+    remexec::execution_stage::Value::CacheCheck => "checking the cache",
+    remexec::execution_stage::Value::Queued => "queued",
+    remexec::execution_stage::Value::Executing => "executing",
+    remexec::execution_stage::Value::Completed => "completed",
+  }
+}
+
+///
+/// Converts the raw value of a `SymlinkAbsolutePathStrategy.Value` field, mapping unrecognized
+/// values to `Unknown` (with a warning) rather than failing.
+///
+pub fn symlink_absolute_path_strategy_from_i32(
+  value: i32,
+) -> remexec::symlink_absolute_path_strategy::Value {
+  remexec::symlink_absolute_path_strategy::Value::from_i32(value).unwrap_or_else(|| {
+    log::warn!("Unrecognized SymlinkAbsolutePathStrategy value: {}", value);
+    remexec::symlink_absolute_path_strategy::Value::Unknown
+  })
+}
+
+///
+/// Returns a canonical string for the properties of the given Platform, for use in cache keys:
+/// properties are sorted by name and then by value, and rendered as `name1=value1;name2=value2`.
+/// Any `\`, `=` or `;` characters in names and values are escaped with a backslash, so that the
+/// string is unambiguous.
+///
+pub fn platform_cache_key(platform: &remexec::Platform) -> String {
+  fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+      .replace('=', "\\=")
+      .replace(';', "\\;")
+  }
+
+  let mut properties = platform
+    .properties
+    .iter()
+    .map(|property| (property.name.as_str(), property.value.as_str()))
+    .collect::<Vec<_>>();
+  properties.sort_unstable();
+  properties
+    .into_iter()
+    .map(|(name, value)| format!("{}={}", escape(name), escape(value)))
+    .collect::<Vec<_>>()
+    .join(";")
+}
+
+///
+/// Returns true if the server has advertised that it allows symlinks with absolute targets. An
+/// unknown strategy is treated as disallowing them.
+///
+pub fn symlinks_absolute_allowed(caps: &remexec::CacheCapabilities) -> bool {
+  symlink_absolute_path_strategy_from_i32(caps.symlink_absolute_path_strategy)
+    == remexec::symlink_absolute_path_strategy::Value::Allowed
+}
+
+///
+/// Returns true if the server has advertised that clients may write to its action cache, which
+/// defaults to false if the server did not advertise any ActionCacheUpdateCapabilities.
+///
+pub fn action_cache_writable(caps: &remexec::CacheCapabilities) -> bool {
+  caps
+    .action_cache_update_capabilities
+    .as_ref()
+    .map(|update_capabilities| update_capabilities.update_enabled)
+    .unwrap_or(false)
+}
+
+///
+/// Returns true if the server has advertised that it supports remote execution, which defaults to
+/// false if the server did not advertise any ExecutionCapabilities.
+///
+pub fn remote_execution_available(caps: &remexec::ServerCapabilities) -> bool {
+  caps
+    .execution_capabilities
+    .as_ref()
+    .map(|execution_capabilities| execution_capabilities.exec_enabled)
+    .unwrap_or(false)
+}
+
+///
+/// How a blob should be uploaded to the CAS.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UploadStrategy {
+  /// The blob is empty, so its content is implied by its digest: callers may skip the upload, or
+  /// send it with `BatchUpdateBlobs` (which is always within the batch limit).
+  Inline,
+  /// The blob fits within the server's advertised batch limit, so may use `BatchUpdateBlobs`.
+  Batch,
+  /// The blob is too large to batch, so must use a ByteStream `Write`.
+  Stream,
+}
+
+///
+/// Picks how to upload a blob of the given size based on the limits advertised by the server. A
+/// `max_batch_total_size_bytes` of 0 means that the server sets no limit for batches.
+///
+/// NB: Newer versions of the REAPI also advertise `max_cas_blob_size_bytes`, but the version
+/// vendored here does not, so batching is bounded only by `max_batch_total_size_bytes`.
+///
+pub fn upload_strategy(caps: &remexec::CacheCapabilities, blob_size: usize) -> UploadStrategy {
+  if blob_size == 0 {
+    return UploadStrategy::Inline;
+  }
+  let max_batch_total_size_bytes = caps.max_batch_total_size_bytes.max(0) as usize;
+  if max_batch_total_size_bytes == 0 || blob_size < max_batch_total_size_bytes {
+    UploadStrategy::Batch
+  } else {
+    UploadStrategy::Stream
+  }
+}
+
+impl std::fmt::Display for SemVer {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if self.prerelease.is_empty() {
+      write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    } else {
+      write!(f, "{}", self.prerelease)
+    }
+  }
+}
+
+///
+/// Parses a `{major}.{minor}[.{patch}]` version string into a SemVer: the reverse of its Display
+/// implementation for released versions.
+///
+pub fn parse_semver(version: &str) -> Result<SemVer, String> {
+  let components = version
+    .split('.')
+    .map(|component| component.parse::<i32>())
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| format!("Bad version {:?}: {}", version, e))?;
+  match components[..] {
+    [major, minor] => Ok(SemVer {
+      major,
+      minor,
+      ..SemVer::default()
+    }),
+    [major, minor, patch] => Ok(SemVer {
+      major,
+      minor,
+      patch,
+      ..SemVer::default()
+    }),
+    _ => Err(format!(
+      "Bad version {:?}: expected `{{major}}.{{minor}}[.{{patch}}]`",
+      version
+    )),
+  }
+}
+
+///
+/// The (major, minor) version used to compare API versions, or None for a pre-release version
+/// (which is assumed to be earlier than any released version).
+///
+fn api_version_key(version: &SemVer) -> Option<(i32, i32)> {
+  if version.prerelease.is_empty() {
+    Some((version.major, version.minor))
+  } else {
+    None
+  }
+}
+
+///
+/// Returns true if our API version falls within the `low_api_version` to `high_api_version` range
+/// advertised by the server (inclusive, comparing major and minor versions). A bound which the
+/// server does not advertise is not checked.
+///
+pub fn api_version_supported(caps: &remexec::ServerCapabilities, ours: &SemVer) -> bool {
+  let ours = api_version_key(ours);
+  let above_low = caps
+    .low_api_version
+    .as_ref()
+    .map(|low| api_version_key(low) <= ours)
+    .unwrap_or(true);
+  let below_high = caps
+    .high_api_version
+    .as_ref()
+    .map(|high| ours <= api_version_key(high))
+    .unwrap_or(true);
+  above_low && below_high
+}
+
+///
+/// Sets the given Platform on the Action (for servers supporting REAPI v2.2 or newer, which moved
+/// the field there) or otherwise on the Command, and clears it from the other message: servers
+/// ignore a Platform in the wrong message, which would cause subtle cache misses.
+///
+pub fn attach_platform(
+  action: &mut remexec::Action,
+  command: &mut remexec::Command,
+  platform: remexec::Platform,
+  api_version: &SemVer,
+) {
+  if api_version_key(api_version) >= Some((2, 2)) {
+    action.platform = Some(platform);
+    command.platform = None;
+  } else {
+    action.platform = None;
+    command.platform = Some(platform);
+  }
+}
+
+///
+/// Clamps the given execution priority into the nearest range supported by the server, or returns
+/// it unchanged if the server does not advertise any ranges.
+///
+pub fn clamp_priority(priority: i32, caps: &remexec::PriorityCapabilities) -> i32 {
+  caps
+    .priorities
+    .iter()
+    .map(|range| {
+      priority.clamp(
+        range.min_priority,
+        range.max_priority.max(range.min_priority),
+      )
+    })
+    .min_by_key(|clamped| (i64::from(*clamped) - i64::from(priority)).abs())
+    .unwrap_or(priority)
+}
+
+impl remexec::Digest {
+  ///
+  /// Returns true if this is the digest of the empty blob, which servers are not required to store
+  /// (and which callers may therefore skip CAS lookups for).
+  ///
+  /// The canonical empty digest is available as `hashing::EMPTY_DIGEST` or `v2::empty_digest()`.
+  ///
+  pub fn is_empty_blob(&self) -> bool {
+    self.size_bytes == 0
+      && self
+        .hash
+        .eq_ignore_ascii_case(&hashing::EMPTY_FINGERPRINT.to_hex())
+  }
+}
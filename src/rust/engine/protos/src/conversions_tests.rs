@@ -1,8 +1,25 @@
 use std::convert::TryInto;
+use std::time::Duration;
 
 use hashing;
+use prost::Message;
 
 use crate::gen::build::bazel::remote::execution::v2 as remexec;
+use crate::gen::build::bazel::semver::SemVer;
+use crate::gen::google::longrunning::Operation;
+use crate::{
+  action_cache_writable, api_version_supported, attach_platform, batch_read_request,
+  clamp_priority, decode_precondition_failure, dedup_digests_to_prost, digest_function_from_i32,
+  digest_to_event_digest, digest_to_resource_name, digests_to_hashing, digests_to_hashing_from,
+  event_digest_to_digest, execute_response_failure_message, execution_stage_from_i32,
+  find_missing_request, find_missing_response_digests, operation_update, parse_batch_read_response,
+  parse_batch_update_response, parse_operation_name_digest, parse_semver, platform_cache_key,
+  read_request, remote_execution_available, request_metadata_from_header,
+  request_metadata_to_header, resource_name_digest, resource_name_digest_and_function,
+  sanitize_unix_mode, snapshot_root_to_directory_digest, stage_label, std_duration_from_prost,
+  symlink_absolute_path_strategy_from_i32, symlinks_absolute_allowed, tree_referenced_digests,
+  upload_strategy, write_request, OperationOutcome, UploadStrategy, REQUEST_METADATA_HEADER,
+};
 
 #[test]
 fn from_our_digest() {
@@ -52,3 +69,1231 @@ fn from_bad_bazel_digest() {
     err
   );
 }
+
+#[test]
+fn from_uppercase_bazel_digest() {
+  let bazel_digest = remexec::Digest {
+    hash: "0123456789ABCDEFFEDCBA98765432100000000000000000FFFFFFFFFFFFFFFF".to_owned(),
+    size_bytes: 10,
+  };
+  let converted: Result<hashing::Digest, String> = (&bazel_digest).try_into();
+  let want = hashing::Digest::new(
+    hashing::Fingerprint::from_hex_string(
+      "0123456789abcdeffedcba98765432100000000000000000ffffffffffffffff",
+    )
+    .unwrap(),
+    10,
+  );
+  assert_eq!(converted, Ok(want));
+}
+
+#[test]
+fn from_non_hex_bazel_digest() {
+  let bazel_digest = remexec::Digest {
+    hash: "g123456789abcdeffedcba98765432100000000000000000ffffffffffffffff".to_owned(),
+    size_bytes: 10,
+  };
+  let converted: Result<hashing::Digest, String> = (&bazel_digest).try_into();
+  let err = converted.expect_err("Want Err converting non-hex digest");
+  assert!(
+    err.contains("not hexadecimal"),
+    "Bad error message: {}",
+    err
+  );
+}
+
+#[test]
+fn from_short_bazel_digest() {
+  let bazel_digest = remexec::Digest {
+    hash: "0123456789abcdef".to_owned(),
+    size_bytes: 10,
+  };
+  let converted: Result<hashing::Digest, String> = (&bazel_digest).try_into();
+  let err = converted.expect_err("Want Err converting short digest");
+  assert!(err.contains("wrong length"), "Bad error message: {}", err);
+}
+
+#[test]
+fn from_negative_size_bazel_digest() {
+  let bazel_digest = remexec::Digest {
+    hash: "0123456789abcdeffedcba98765432100000000000000000ffffffffffffffff".to_owned(),
+    size_bytes: -1,
+  };
+  let converted: Result<hashing::Digest, String> = (&bazel_digest).try_into();
+  let err = converted.expect_err("Want Err converting negative size");
+  assert!(
+    err.starts_with("Negative size_bytes"),
+    "Bad error message: {}",
+    err
+  );
+}
+
+const FIVE_GB: i64 = 5 * 1024 * 1024 * 1024;
+
+#[cfg(target_pointer_width = "32")]
+#[test]
+fn from_oversized_bazel_digest_on_32_bit() {
+  let bazel_digest = remexec::Digest {
+    hash: "0123456789abcdeffedcba98765432100000000000000000ffffffffffffffff".to_owned(),
+    size_bytes: FIVE_GB,
+  };
+  let converted: Result<hashing::Digest, String> = (&bazel_digest).try_into();
+  let err = converted.expect_err("Want Err converting oversized digest");
+  assert!(
+    err.contains("does not fit in a 32-bit usize"),
+    "Bad error message: {}",
+    err
+  );
+}
+
+#[cfg(target_pointer_width = "64")]
+#[test]
+fn from_large_bazel_digest_on_64_bit() {
+  let bazel_digest = remexec::Digest {
+    hash: "0123456789abcdeffedcba98765432100000000000000000ffffffffffffffff".to_owned(),
+    size_bytes: FIVE_GB,
+  };
+  let converted: hashing::Digest = (&bazel_digest).try_into().unwrap();
+  assert_eq!(converted.size_bytes as i64, FIVE_GB);
+}
+
+#[test]
+fn snapshot_root_to_directory_digest_matches_generic_conversion() {
+  let directory = remexec::Directory {
+    files: vec![file_node("roland", hashing::Digest::of_bytes(b"roland"))],
+    ..remexec::Directory::default()
+  };
+  let digest = hashing::Digest::of_bytes(&directory.encode_to_vec());
+  assert_eq!(
+    snapshot_root_to_directory_digest(&digest),
+    remexec::Digest::from(&digest)
+  );
+}
+
+#[test]
+fn event_digest_round_trip() {
+  let digest = hashing::Digest::of_bytes(b"roland");
+  let event_digest = digest_to_event_digest(&digest);
+  assert_eq!(event_digest, remexec::Digest::from(&digest));
+  assert_eq!(event_digest_to_digest(&event_digest), Ok(digest));
+
+  let err = event_digest_to_digest(&remexec::Digest {
+    hash: "not-a-fingerprint".to_owned(),
+    size_bytes: 6,
+  })
+  .expect_err("Want Err for a bad fingerprint");
+  assert!(err.contains("build event"), "Bad error message: {}", err);
+}
+
+#[test]
+fn operation_update_decodes_execute_operation_metadata() {
+  let action_digest = remexec::Digest {
+    hash: "0123456789abcdeffedcba98765432100000000000000000ffffffffffffffff".to_owned(),
+    size_bytes: 10,
+  };
+  let metadata = remexec::ExecuteOperationMetadata {
+    stage: remexec::execution_stage::Value::CacheCheck as i32,
+    action_digest: Some(action_digest.clone()),
+    ..remexec::ExecuteOperationMetadata::default()
+  };
+  let operation = Operation {
+    name: "cat".to_owned(),
+    metadata: Some(prost_types::Any {
+      type_url: "type.googleapis.com/build.bazel.remote.execution.v2.ExecuteOperationMetadata"
+        .to_owned(),
+      value: metadata.encode_to_vec(),
+    }),
+    done: false,
+    result: None,
+  };
+
+  let update = operation_update(operation.clone()).unwrap();
+  assert_eq!(update.operation, operation);
+  assert_eq!(
+    update.stage,
+    Some(remexec::execution_stage::Value::CacheCheck)
+  );
+  assert_eq!(
+    update.action_digest,
+    Some((&action_digest).try_into().unwrap())
+  );
+}
+
+#[test]
+fn operation_update_without_metadata() {
+  let operation = Operation {
+    name: "cat".to_owned(),
+    ..Operation::default()
+  };
+
+  let update = operation_update(operation).unwrap();
+  assert_eq!(update.stage, None);
+  assert_eq!(update.action_digest, None);
+}
+
+#[test]
+fn operation_outcome_pending() {
+  let metadata = remexec::ExecuteOperationMetadata {
+    stage: remexec::execution_stage::Value::Executing as i32,
+    ..remexec::ExecuteOperationMetadata::default()
+  };
+  let operation = Operation {
+    name: "cat".to_owned(),
+    metadata: Some(prost_types::Any {
+      type_url: "type.googleapis.com/build.bazel.remote.execution.v2.ExecuteOperationMetadata"
+        .to_owned(),
+      value: metadata.encode_to_vec(),
+    }),
+    done: false,
+    result: None,
+  };
+
+  assert_eq!(
+    operation_update(operation).unwrap().outcome(),
+    Ok(OperationOutcome::Pending(Some(
+      remexec::execution_stage::Value::Executing
+    )))
+  );
+}
+
+#[test]
+fn operation_outcome_failed() {
+  use crate::gen::google::longrunning::operation;
+  let status = crate::gen::google::rpc::Status {
+    code: tonic::Code::Internal as i32,
+    message: "Worker lost.".to_owned(),
+    ..crate::gen::google::rpc::Status::default()
+  };
+  let operation = Operation {
+    name: "cat".to_owned(),
+    done: true,
+    result: Some(operation::Result::Error(status.clone())),
+    ..Operation::default()
+  };
+
+  assert_eq!(
+    operation_update(operation).unwrap().outcome(),
+    Ok(OperationOutcome::Failed(status))
+  );
+}
+
+#[test]
+fn operation_outcome_completed() {
+  use crate::gen::google::longrunning::operation;
+  let response = remexec::ExecuteResponse {
+    result: Some(remexec::ActionResult {
+      exit_code: 0,
+      ..remexec::ActionResult::default()
+    }),
+    ..remexec::ExecuteResponse::default()
+  };
+  let operation = Operation {
+    name: "cat".to_owned(),
+    done: true,
+    result: Some(operation::Result::Response(prost_types::Any {
+      type_url: "type.googleapis.com/build.bazel.remote.execution.v2.ExecuteResponse".to_owned(),
+      value: response.encode_to_vec(),
+    })),
+    ..Operation::default()
+  };
+
+  assert_eq!(
+    operation_update(operation).unwrap().outcome(),
+    Ok(OperationOutcome::Completed(response))
+  );
+
+  let no_result = Operation {
+    name: "cat".to_owned(),
+    done: true,
+    ..Operation::default()
+  };
+  assert!(operation_update(no_result).unwrap().outcome().is_err());
+}
+
+#[test]
+fn directory_strip_symlinks() {
+  let file = remexec::FileNode {
+    name: "roland".to_owned(),
+    digest: Some(remexec::empty_digest()),
+    is_executable: true,
+    ..remexec::FileNode::default()
+  };
+  let subdirectory = remexec::DirectoryNode {
+    name: "cats".to_owned(),
+    digest: Some(remexec::empty_digest()),
+  };
+  let directory = remexec::Directory {
+    files: vec![file.clone()],
+    directories: vec![subdirectory.clone()],
+    symlinks: vec![remexec::SymlinkNode {
+      name: "link".to_owned(),
+      target: "roland".to_owned(),
+      ..remexec::SymlinkNode::default()
+    }],
+    ..remexec::Directory::default()
+  };
+
+  let stripped = directory.strip_symlinks();
+  assert_eq!(stripped.files, vec![file]);
+  assert_eq!(stripped.directories, vec![subdirectory]);
+  assert!(stripped.symlinks.is_empty());
+  // The original is unmodified.
+  assert_eq!(directory.symlinks.len(), 1);
+}
+
+fn output_symlink(path: &str, target: &str) -> remexec::OutputSymlink {
+  remexec::OutputSymlink {
+    path: path.to_owned(),
+    target: target.to_owned(),
+    ..remexec::OutputSymlink::default()
+  }
+}
+
+#[test]
+fn action_result_merge_symlink_fields() {
+  let mut action_result = remexec::ActionResult {
+    output_file_symlinks: vec![
+      output_symlink("file_link", "roland"),
+      output_symlink("both_link", "roland"),
+    ],
+    output_directory_symlinks: vec![output_symlink("directory_link", "cats")],
+    output_symlinks: vec![output_symlink("both_link", "roland")],
+    ..remexec::ActionResult::default()
+  };
+
+  action_result.merge_symlink_fields();
+  assert_eq!(
+    action_result.output_symlinks,
+    vec![
+      output_symlink("both_link", "roland"),
+      output_symlink("file_link", "roland"),
+      output_symlink("directory_link", "cats"),
+    ]
+  );
+  assert!(action_result.output_file_symlinks.is_empty());
+  assert!(action_result.output_directory_symlinks.is_empty());
+}
+
+#[test]
+fn command_outputs_split_fields() {
+  let mut command = remexec::Command::default();
+  command.set_outputs(vec!["out.txt".to_owned()], vec!["outdir".to_owned()], false);
+  assert!(command.output_paths.is_empty());
+  assert_eq!(command.output_files, vec!["out.txt".to_owned()]);
+  assert_eq!(command.output_directories, vec!["outdir".to_owned()]);
+  assert_eq!(
+    command.split_outputs(),
+    (vec!["out.txt".to_owned()], vec!["outdir".to_owned()])
+  );
+}
+
+#[test]
+fn command_outputs_output_paths() {
+  let mut command = remexec::Command::default();
+  command.set_outputs(vec!["out.txt".to_owned()], vec!["outdir".to_owned()], true);
+  assert!(command.output_files.is_empty());
+  assert!(command.output_directories.is_empty());
+  assert_eq!(
+    command.output_paths,
+    vec!["out.txt".to_owned(), "outdir".to_owned()]
+  );
+
+  // Without the split fields, output paths can only be assumed to be files.
+  assert_eq!(
+    command.split_outputs(),
+    (vec!["out.txt".to_owned(), "outdir".to_owned()], vec![])
+  );
+
+  // But a server may populate both forms.
+  command.output_directories = vec!["outdir".to_owned()];
+  assert_eq!(
+    command.split_outputs(),
+    (vec!["out.txt".to_owned()], vec!["outdir".to_owned()])
+  );
+}
+
+#[test]
+fn batch_read_request_preserves_order() {
+  let digests = vec![
+    hashing::Digest::of_bytes(b"roland"),
+    hashing::EMPTY_DIGEST,
+    hashing::Digest::of_bytes(b"cats"),
+  ];
+  let request = batch_read_request("instance".to_owned(), &digests);
+  assert_eq!(request.instance_name, "instance");
+  assert_eq!(
+    request.digests,
+    digests
+      .iter()
+      .map(remexec::Digest::from)
+      .collect::<Vec<_>>()
+  );
+}
+
+fn file_node(name: &str, digest: hashing::Digest) -> remexec::FileNode {
+  remexec::FileNode {
+    name: name.to_owned(),
+    digest: Some(digest.into()),
+    ..remexec::FileNode::default()
+  }
+}
+
+#[test]
+fn dedup_digests_to_prost_first_seen_order() {
+  let roland = hashing::Digest::of_bytes(b"roland");
+  let cats = hashing::Digest::of_bytes(b"cats");
+  let dogs = hashing::Digest::of_bytes(b"dogs");
+  assert_eq!(
+    dedup_digests_to_prost(&[cats, roland, cats, dogs, roland, cats]),
+    vec![
+      remexec::Digest::from(cats),
+      remexec::Digest::from(roland),
+      remexec::Digest::from(dogs),
+    ]
+  );
+}
+
+#[test]
+fn find_missing_blobs() {
+  let digests = vec![
+    hashing::Digest::of_bytes(b"roland"),
+    hashing::Digest::of_bytes(b"cats"),
+    hashing::Digest::of_bytes(b"dogs"),
+  ];
+  let request = find_missing_request("instance".to_owned(), &digests);
+  assert_eq!(request.instance_name, "instance");
+  assert_eq!(
+    request.blob_digests,
+    digests
+      .iter()
+      .map(remexec::Digest::from)
+      .collect::<Vec<_>>()
+  );
+
+  let response = remexec::FindMissingBlobsResponse {
+    missing_blob_digests: vec![
+      request.blob_digests[0].clone(),
+      request.blob_digests[2].clone(),
+    ],
+  };
+  assert_eq!(
+    find_missing_response_digests(&response),
+    Ok(vec![digests[0], digests[2]])
+  );
+}
+
+#[test]
+fn find_missing_response_bad_digest() {
+  let response = remexec::FindMissingBlobsResponse {
+    missing_blob_digests: vec![
+      hashing::Digest::of_bytes(b"roland").into(),
+      remexec::Digest {
+        hash: "0".to_owned(),
+        size_bytes: 1,
+      },
+    ],
+  };
+  let err = find_missing_response_digests(&response).expect_err("Want Err for a bad digest");
+  assert!(err.contains("at index 1"), "Bad error message: {}", err);
+}
+
+#[test]
+fn tree_referenced_digests_across_directories() {
+  let roland = hashing::Digest::of_bytes(b"roland");
+  let cats = hashing::Digest::of_bytes(b"cats");
+  let dogs = hashing::Digest::of_bytes(b"dogs");
+  let tree = remexec::Tree {
+    root: Some(remexec::Directory {
+      files: vec![file_node("roland", roland)],
+      ..remexec::Directory::default()
+    }),
+    children: vec![remexec::Directory {
+      files: vec![file_node("cats", cats), file_node("dogs", dogs)],
+      ..remexec::Directory::default()
+    }],
+  };
+
+  assert_eq!(tree_referenced_digests(&tree), Ok(vec![roland, cats, dogs]));
+}
+
+#[test]
+fn tree_referenced_digests_bad_digest() {
+  let tree = remexec::Tree {
+    root: Some(remexec::Directory::default()),
+    children: vec![remexec::Directory {
+      files: vec![remexec::FileNode {
+        name: "roland".to_owned(),
+        digest: Some(remexec::Digest {
+          hash: "0".to_owned(),
+          size_bytes: 1,
+        }),
+        ..remexec::FileNode::default()
+      }],
+      ..remexec::Directory::default()
+    }],
+  };
+
+  let err = tree_referenced_digests(&tree).expect_err("Want Err for a bad file digest");
+  assert!(
+    err.contains("\"roland\" in child 0 directory"),
+    "Bad error message: {}",
+    err
+  );
+}
+
+#[test]
+fn sanitize_unix_mode_permission_bits() {
+  assert_eq!(sanitize_unix_mode(0o644), 0o644);
+  assert_eq!(sanitize_unix_mode(0o755), 0o755);
+}
+
+#[test]
+fn sanitize_unix_mode_strips_setuid() {
+  assert_eq!(sanitize_unix_mode(0o4755), 0o755);
+  // The setgid and sticky bits are stripped as well, as are any bits above 0o7777.
+  assert_eq!(sanitize_unix_mode(0o2750), 0o750);
+  assert_eq!(sanitize_unix_mode(0o1777), 0o777);
+  assert_eq!(sanitize_unix_mode(0o7777), 0o777);
+  assert_eq!(sanitize_unix_mode(0o100644), 0o644);
+
+  let properties = remexec::NodeProperties {
+    unix_mode: Some(0o4755),
+    ..remexec::NodeProperties::default()
+  };
+  assert_eq!(properties.sanitized_unix_mode(), Some(0o755));
+  assert_eq!(
+    remexec::NodeProperties::default().sanitized_unix_mode(),
+    None
+  );
+}
+
+fn directory_node(name: &str, directory: &remexec::Directory) -> remexec::DirectoryNode {
+  remexec::DirectoryNode {
+    name: name.to_owned(),
+    digest: Some(hashing::Digest::of_bytes(&directory.encode_to_vec()).into()),
+  }
+}
+
+#[test]
+fn tree_validate_topological_order() {
+  let leaf = remexec::Directory {
+    files: vec![file_node("roland", hashing::Digest::of_bytes(b"roland"))],
+    ..remexec::Directory::default()
+  };
+  let middle = remexec::Directory {
+    directories: vec![directory_node("leaf", &leaf)],
+    ..remexec::Directory::default()
+  };
+  let root = remexec::Directory {
+    directories: vec![directory_node("middle", &middle)],
+    ..remexec::Directory::default()
+  };
+
+  let sorted = remexec::Tree {
+    root: Some(root.clone()),
+    children: vec![middle.clone(), leaf.clone()],
+  };
+  assert_eq!(sorted.validate_topological_order(), Ok(()));
+
+  let reversed = remexec::Tree {
+    root: Some(root),
+    children: vec![leaf.clone(), middle],
+  };
+  let err = reversed
+    .validate_topological_order()
+    .expect_err("Want Err for a child preceding its parent");
+  let leaf_digest = hashing::Digest::of_bytes(&leaf.encode_to_vec());
+  assert!(
+    err.contains(&format!("{:?}", leaf_digest)),
+    "Bad error message: {}",
+    err
+  );
+}
+
+#[test]
+fn digest_function_known_values() {
+  use remexec::digest_function::Value;
+  for value in [
+    Value::Unknown,
+    Value::Sha256,
+    Value::Sha1,
+    Value::Md5,
+    Value::Vso,
+    Value::Sha384,
+    Value::Sha512,
+    Value::Murmur3,
+  ] {
+    assert_eq!(digest_function_from_i32(value as i32), value);
+  }
+}
+
+#[test]
+fn digest_function_unknown_value() {
+  assert_eq!(
+    digest_function_from_i32(1000),
+    remexec::digest_function::Value::Unknown
+  );
+  assert_eq!(
+    digest_function_from_i32(-1),
+    remexec::digest_function::Value::Unknown
+  );
+}
+
+#[test]
+fn is_empty_blob() {
+  assert!(remexec::empty_digest().is_empty_blob());
+  assert!(remexec::Digest::from(hashing::EMPTY_DIGEST).is_empty_blob());
+
+  // A zero size with a non-empty hash is malformed, and is not the empty blob.
+  let zero_sized = remexec::Digest {
+    hash: "0123456789abcdeffedcba98765432100000000000000000ffffffffffffffff".to_owned(),
+    size_bytes: 0,
+  };
+  assert!(!zero_sized.is_empty_blob());
+
+  let wrong_size = remexec::Digest {
+    size_bytes: 1,
+    ..remexec::empty_digest()
+  };
+  assert!(!wrong_size.is_empty_blob());
+}
+
+#[test]
+fn duration_round_trip() {
+  let duration = Duration::from_millis(90500);
+  let prost_duration = prost_types::Duration::from(duration);
+  assert_eq!(
+    prost_duration,
+    prost_types::Duration {
+      seconds: 90,
+      nanos: 500_000_000,
+    }
+  );
+  assert_eq!(std_duration_from_prost(&prost_duration), duration);
+}
+
+#[test]
+fn negative_duration_saturates() {
+  let negative = prost_types::Duration {
+    seconds: -90,
+    nanos: -500_000_000,
+  };
+  assert_eq!(std_duration_from_prost(&negative), Duration::ZERO);
+
+  // Mixed signs are not normalized, but are still interpreted as their total.
+  let mixed = prost_types::Duration {
+    seconds: 1,
+    nanos: -500_000_000,
+  };
+  assert_eq!(std_duration_from_prost(&mixed), Duration::from_millis(500));
+}
+
+fn priority_capabilities(ranges: &[(i32, i32)]) -> remexec::PriorityCapabilities {
+  remexec::PriorityCapabilities {
+    priorities: ranges
+      .iter()
+      .map(
+        |&(min_priority, max_priority)| remexec::priority_capabilities::PriorityRange {
+          min_priority,
+          max_priority,
+        },
+      )
+      .collect(),
+  }
+}
+
+#[test]
+fn clamp_priority_single_range() {
+  let caps = priority_capabilities(&[(-10, 10)]);
+  assert_eq!(clamp_priority(5, &caps), 5);
+  assert_eq!(clamp_priority(20, &caps), 10);
+  assert_eq!(clamp_priority(-20, &caps), -10);
+}
+
+#[test]
+fn clamp_priority_multiple_ranges() {
+  let caps = priority_capabilities(&[(0, 10), (100, 200)]);
+  assert_eq!(clamp_priority(150, &caps), 150);
+  assert_eq!(clamp_priority(40, &caps), 10);
+  assert_eq!(clamp_priority(70, &caps), 100);
+  assert_eq!(clamp_priority(300, &caps), 200);
+}
+
+#[test]
+fn clamp_priority_no_ranges() {
+  assert_eq!(clamp_priority(300, &priority_capabilities(&[])), 300);
+}
+
+#[test]
+fn digests_to_hashing_all_valid() {
+  let digests: Vec<remexec::Digest> = vec![
+    hashing::Digest::of_bytes(b"roland").into(),
+    hashing::Digest::of_bytes(b"catnip").into(),
+  ];
+  let converted = digests_to_hashing(&digests).unwrap();
+  assert_eq!(
+    converted
+      .iter()
+      .map(|digest| digest.into())
+      .collect::<Vec<remexec::Digest>>(),
+    digests
+  );
+}
+
+#[test]
+fn digests_to_hashing_bad_digest() {
+  let digests: Vec<remexec::Digest> = vec![
+    hashing::Digest::of_bytes(b"roland").into(),
+    hashing::Digest::of_bytes(b"catnip").into(),
+    remexec::Digest {
+      hash: "0".to_owned(),
+      size_bytes: 1,
+    },
+  ];
+  let result = digests_to_hashing(&digests);
+  assert!(
+    matches!(result, Err(ref e) if e.contains("at index 2")),
+    "Bad result: {:?}",
+    result
+  );
+
+  // A chunk reports the index of the bad digest in the complete list.
+  assert_eq!(digests_to_hashing_from(&digests[1..], 1), result);
+}
+
+#[test]
+fn execution_stages() {
+  use remexec::execution_stage::Value;
+  let stages = [
+    Value::Unknown,
+    Value::CacheCheck,
+    Value::Queued,
+    Value::Executing,
+    Value::Completed,
+  ];
+  for stage in stages {
+    assert_eq!(execution_stage_from_i32(stage as i32), stage);
+  }
+  assert_eq!(execution_stage_from_i32(1000), Value::Unknown);
+
+  let labels = stages
+    .iter()
+    .map(|stage| stage_label(*stage))
+    .collect::<std::collections::HashSet<_>>();
+  assert_eq!(labels.len(), stages.len());
+}
+
+#[test]
+fn bytestream_requests() {
+  let write = write_request(
+    "instance/uploads/uuid/blobs/abc/3".to_owned(),
+    10,
+    bytes::Bytes::from_static(b"cat"),
+    true,
+  );
+  assert_eq!(write.resource_name, "instance/uploads/uuid/blobs/abc/3");
+  assert_eq!(write.write_offset, 10);
+  assert_eq!(write.data, bytes::Bytes::from_static(b"cat"));
+  assert!(write.finish_write);
+
+  let read = read_request("instance/blobs/abc/3".to_owned(), 1, 2);
+  assert_eq!(read.resource_name, "instance/blobs/abc/3");
+  assert_eq!(read.read_offset, 1);
+  assert_eq!(read.read_limit, 2);
+}
+
+#[test]
+fn resource_name_digest_valid() {
+  let digest = hashing::Digest::of_bytes(b"roland");
+  for resource_name in [
+    format!("blobs/{}/{}", digest.hash, digest.size_bytes),
+    format!("instance/blobs/{}/{}", digest.hash, digest.size_bytes),
+    format!(
+      "instance/uploads/uuid/blobs/{}/{}/metadata",
+      digest.hash, digest.size_bytes
+    ),
+  ] {
+    assert_eq!(resource_name_digest(&resource_name), Ok(digest));
+  }
+}
+
+#[test]
+fn resource_name_digest_malformed() {
+  let digest = hashing::Digest::of_bytes(b"roland");
+  for (resource_name, expected_error) in [
+    ("instance/blobs".to_owned(), "Malformed resource name"),
+    (
+      format!("instance/blobs/{}", digest.hash),
+      "Malformed resource name",
+    ),
+    ("instance/blobs/abc/6".to_owned(), "Bad hash"),
+    (format!("instance/blobs/{}/-6", digest.hash), "Bad size"),
+  ] {
+    let err = resource_name_digest(&resource_name).expect_err("Want Err for a malformed name");
+    assert!(err.contains(expected_error), "Bad error message: {}", err);
+  }
+}
+
+#[test]
+fn symlink_absolute_path_strategies() {
+  use remexec::symlink_absolute_path_strategy::Value;
+  for value in [Value::Unknown, Value::Disallowed, Value::Allowed] {
+    assert_eq!(symlink_absolute_path_strategy_from_i32(value as i32), value);
+  }
+  assert_eq!(
+    symlink_absolute_path_strategy_from_i32(1000),
+    Value::Unknown
+  );
+}
+
+#[test]
+fn symlinks_absolute_allowed_by_strategy() {
+  use remexec::symlink_absolute_path_strategy::Value;
+  let caps = |strategy: Value| remexec::CacheCapabilities {
+    symlink_absolute_path_strategy: strategy as i32,
+    ..remexec::CacheCapabilities::default()
+  };
+  assert!(symlinks_absolute_allowed(&caps(Value::Allowed)));
+  assert!(!symlinks_absolute_allowed(&caps(Value::Disallowed)));
+  assert!(!symlinks_absolute_allowed(&caps(Value::Unknown)));
+}
+
+#[test]
+fn action_cache_writable_by_capabilities() {
+  let caps = |update_enabled: Option<bool>| remexec::CacheCapabilities {
+    action_cache_update_capabilities: update_enabled
+      .map(|update_enabled| remexec::ActionCacheUpdateCapabilities { update_enabled }),
+    ..remexec::CacheCapabilities::default()
+  };
+  assert!(action_cache_writable(&caps(Some(true))));
+  assert!(!action_cache_writable(&caps(Some(false))));
+  assert!(!action_cache_writable(&caps(None)));
+}
+
+#[test]
+fn digest_to_resource_name_with_instance() {
+  let digest = hashing::Digest::of_bytes(b"roland");
+  let resource_name = digest_to_resource_name("main/instance", &digest, None);
+  assert_eq!(
+    resource_name,
+    format!("main/instance/blobs/{}/6", digest.hash.to_hex())
+  );
+  assert_eq!(resource_name_digest(&resource_name), Ok(digest));
+}
+
+#[test]
+fn digest_to_resource_name_without_instance() {
+  let digest = hashing::Digest::of_bytes(b"roland");
+  let resource_name = digest_to_resource_name("", &digest, None);
+  assert_eq!(resource_name, format!("blobs/{}/6", digest.hash.to_hex()));
+  assert_eq!(resource_name_digest(&resource_name), Ok(digest));
+}
+
+#[test]
+fn digest_to_resource_name_with_digest_function() {
+  use remexec::digest_function::Value;
+  let digest = hashing::Digest::of_bytes(b"roland");
+  let resource_name = digest_to_resource_name("instance", &digest, Some(Value::Sha256));
+  assert_eq!(
+    resource_name,
+    format!("instance/blobs/sha256/{}/6", digest.hash.to_hex())
+  );
+  assert_eq!(
+    resource_name_digest_and_function(&resource_name),
+    Ok((digest, Value::Sha256))
+  );
+
+  // An Unknown digest function is omitted.
+  assert_eq!(
+    digest_to_resource_name("instance", &digest, Some(Value::Unknown)),
+    digest_to_resource_name("instance", &digest, None),
+  );
+}
+
+#[test]
+fn resource_name_digest_and_function_layouts() {
+  use remexec::digest_function::Value;
+  let digest = hashing::Digest::of_bytes(b"roland");
+  for (resource_name, digest_function) in [
+    (format!("instance/blobs/{}/6", digest.hash), Value::Sha256),
+    (
+      format!("instance/blobs/{}/6/metadata", digest.hash),
+      Value::Sha256,
+    ),
+    (
+      format!("instance/blobs/sha256/{}/6", digest.hash),
+      Value::Sha256,
+    ),
+    (
+      format!("instance/uploads/uuid/blobs/sha384/{}/6", digest.hash),
+      Value::Sha384,
+    ),
+  ] {
+    assert_eq!(
+      resource_name_digest_and_function(&resource_name),
+      Ok((digest, digest_function))
+    );
+  }
+
+  // BLAKE3 is not a DigestFunction in the REAPI version that we use.
+  let err = resource_name_digest_and_function(&format!("instance/blobs/blake3/{}/6", digest.hash))
+    .expect_err("Want Err for an unsupported digest function");
+  assert!(
+    err.contains("Unsupported digest function \"blake3\""),
+    "Bad error message: {}",
+    err
+  );
+}
+
+#[test]
+fn parse_operation_name_digest_embedded() {
+  let digest = hashing::Digest::of_bytes(b"roland");
+  assert_eq!(
+    parse_operation_name_digest(&format!(
+      "instance/operations/uuid/actions/{}/{}",
+      digest.hash, digest.size_bytes
+    )),
+    Some(digest)
+  );
+}
+
+#[test]
+fn parse_operation_name_digest_absent() {
+  let digest = hashing::Digest::of_bytes(b"roland");
+  for name in [
+    "".to_owned(),
+    "operations/1234-5678".to_owned(),
+    "instance/actions/abc/6".to_owned(),
+    format!("instance/actions/{}", digest.hash),
+    format!("instance/actions/{}/-6", digest.hash),
+  ] {
+    assert_eq!(parse_operation_name_digest(&name), None, "For {:?}", name);
+  }
+}
+
+#[test]
+fn decode_precondition_failure_missing() {
+  use crate::gen::google::rpc;
+  let violation = rpc::precondition_failure::Violation {
+    r#type: "MISSING".to_owned(),
+    subject: "blobs/abc/3".to_owned(),
+    description: "The blob is missing.".to_owned(),
+  };
+  let status = rpc::Status {
+    code: 9,
+    message: "Missing blobs".to_owned(),
+    details: vec![
+      prost_types::Any {
+        type_url: "type.googleapis.com/google.rpc.DebugInfo".to_owned(),
+        value: vec![],
+      },
+      prost_types::Any {
+        type_url: "type.googleapis.com/google.rpc.PreconditionFailure".to_owned(),
+        value: rpc::PreconditionFailure {
+          violations: vec![violation.clone()],
+        }
+        .encode_to_vec(),
+      },
+    ],
+  };
+  assert_eq!(decode_precondition_failure(&status), vec![violation]);
+  assert!(decode_precondition_failure(&rpc::Status::default()).is_empty());
+}
+
+#[test]
+fn parse_batch_read_response_partial_success() {
+  let roland = hashing::Digest::of_bytes(b"roland");
+  let missing = hashing::Digest::of_bytes(b"missing");
+  let response = remexec::BatchReadBlobsResponse {
+    responses: vec![
+      remexec::batch_read_blobs_response::Response {
+        digest: Some(roland.into()),
+        data: bytes::Bytes::from_static(b"roland"),
+        status: Some(crate::gen::google::rpc::Status {
+          code: tonic::Code::Ok as i32,
+          ..crate::gen::google::rpc::Status::default()
+        }),
+      },
+      remexec::batch_read_blobs_response::Response {
+        digest: Some(missing.into()),
+        data: bytes::Bytes::new(),
+        status: Some(crate::gen::google::rpc::Status {
+          code: tonic::Code::NotFound as i32,
+          message: "No such blob.".to_owned(),
+          ..crate::gen::google::rpc::Status::default()
+        }),
+      },
+    ],
+  };
+
+  assert_eq!(
+    parse_batch_read_response(response),
+    Ok(vec![
+      Ok((roland, bytes::Bytes::from_static(b"roland"))),
+      Err((missing, "NotFound: No such blob.".to_owned())),
+    ])
+  );
+}
+
+#[test]
+fn parse_batch_read_response_bad_digest() {
+  let response = remexec::BatchReadBlobsResponse {
+    responses: vec![remexec::batch_read_blobs_response::Response::default()],
+  };
+  let err = parse_batch_read_response(response).expect_err("Want Err for a missing digest");
+  assert!(err.contains("entry 0"), "Bad error message: {}", err);
+}
+
+#[test]
+fn parse_batch_update_response_partial_success() {
+  let roland = hashing::Digest::of_bytes(b"roland");
+  let exhausted = hashing::Digest::of_bytes(b"exhausted");
+  let response = remexec::BatchUpdateBlobsResponse {
+    responses: vec![
+      remexec::batch_update_blobs_response::Response {
+        digest: Some(roland.into()),
+        status: Some(crate::gen::google::rpc::Status {
+          code: tonic::Code::Ok as i32,
+          ..crate::gen::google::rpc::Status::default()
+        }),
+      },
+      remexec::batch_update_blobs_response::Response {
+        digest: Some(exhausted.into()),
+        status: Some(crate::gen::google::rpc::Status {
+          code: tonic::Code::ResourceExhausted as i32,
+          message: "Out of space.".to_owned(),
+          ..crate::gen::google::rpc::Status::default()
+        }),
+      },
+    ],
+  };
+
+  assert_eq!(
+    parse_batch_update_response(response),
+    Ok(vec![
+      (roland, Ok(())),
+      (
+        exhausted,
+        Err("ResourceExhausted: Out of space.".to_owned())
+      ),
+    ])
+  );
+}
+
+#[test]
+fn execute_response_failure_message_failed_precondition() {
+  let response = remexec::ExecuteResponse {
+    status: Some(crate::gen::google::rpc::Status {
+      code: tonic::Code::FailedPrecondition as i32,
+      message: "Missing inputs.".to_owned(),
+      ..crate::gen::google::rpc::Status::default()
+    }),
+    message: "See the server logs for details.".to_owned(),
+    ..remexec::ExecuteResponse::default()
+  };
+  let message =
+    execute_response_failure_message(&response).expect("Want a message for a failed response");
+  for expected in [
+    "FailedPrecondition",
+    "Missing inputs.",
+    "See the server logs for details.",
+  ] {
+    assert!(message.contains(expected), "Bad message: {}", message);
+  }
+
+  assert_eq!(
+    execute_response_failure_message(&remexec::ExecuteResponse::default()),
+    None
+  );
+}
+
+#[test]
+fn request_metadata_header_round_trip() {
+  let metadata = remexec::RequestMetadata {
+    tool_details: Some(remexec::ToolDetails {
+      tool_name: "pants".to_owned(),
+      tool_version: "2.9.0".to_owned(),
+    }),
+    tool_invocation_id: "marmosets".to_owned(),
+    action_id: "roland".to_owned(),
+    ..remexec::RequestMetadata::default()
+  };
+
+  let (key, value) = request_metadata_to_header(&metadata);
+  assert_eq!(key, REQUEST_METADATA_HEADER);
+  assert_eq!(request_metadata_from_header(&value), Ok(metadata));
+
+  assert!(request_metadata_from_header(b"\xff").is_err());
+}
+
+#[test]
+fn semver_round_trip() {
+  let version = parse_semver("2.1.3").unwrap();
+  assert_eq!(
+    version,
+    SemVer {
+      major: 2,
+      minor: 1,
+      patch: 3,
+      ..SemVer::default()
+    }
+  );
+  assert_eq!(version.to_string(), "2.1.3");
+  assert_eq!(parse_semver("2.0").unwrap().to_string(), "2.0.0");
+
+  for version in ["", "2", "2.x", "2.1.3.4"] {
+    assert!(parse_semver(version).is_err(), "Want Err for {:?}", version);
+  }
+}
+
+#[test]
+fn api_version_supported_by_range() {
+  let caps = remexec::ServerCapabilities {
+    low_api_version: Some(parse_semver("2.0").unwrap()),
+    high_api_version: Some(parse_semver("2.2").unwrap()),
+    ..remexec::ServerCapabilities::default()
+  };
+  // Patch versions are not compared.
+  assert!(api_version_supported(
+    &caps,
+    &parse_semver("2.2.7").unwrap()
+  ));
+  assert!(api_version_supported(&caps, &parse_semver("2.0").unwrap()));
+  assert!(!api_version_supported(&caps, &parse_semver("1.9").unwrap()));
+  assert!(!api_version_supported(&caps, &parse_semver("2.3").unwrap()));
+
+  // Pre-release versions are earlier than any release.
+  let prerelease = SemVer {
+    prerelease: "2.1-rc1".to_owned(),
+    ..SemVer::default()
+  };
+  assert!(!api_version_supported(&caps, &prerelease));
+
+  // Bounds which are not advertised are not checked.
+  assert!(api_version_supported(
+    &remexec::ServerCapabilities::default(),
+    &parse_semver("2.3").unwrap()
+  ));
+}
+
+fn platform(properties: &[(&str, &str)]) -> remexec::Platform {
+  remexec::Platform {
+    properties: properties
+      .iter()
+      .map(|(name, value)| remexec::platform::Property {
+        name: (*name).to_owned(),
+        value: (*value).to_owned(),
+      })
+      .collect(),
+  }
+}
+
+#[test]
+fn platform_cache_key_canonical() {
+  let key = platform_cache_key(&platform(&[("OSFamily", "linux"), ("Arch", "x86_64")]));
+  assert_eq!(key, "Arch=x86_64;OSFamily=linux");
+  assert_eq!(
+    platform_cache_key(&platform(&[("Arch", "x86_64"), ("OSFamily", "linux")])),
+    key
+  );
+
+  assert_ne!(
+    platform_cache_key(&platform(&[("Arch", "aarch64"), ("OSFamily", "linux")])),
+    key
+  );
+  // Separators within names and values are escaped.
+  assert_ne!(
+    platform_cache_key(&platform(&[("a=b", "c")])),
+    platform_cache_key(&platform(&[("a", "b=c")]))
+  );
+}
+
+#[test]
+fn attach_platform_to_action() {
+  let platform = platform(&[("OSFamily", "linux")]);
+  let mut action = remexec::Action::default();
+  let mut command = remexec::Command {
+    platform: Some(remexec::Platform::default()),
+    ..remexec::Command::default()
+  };
+  attach_platform(
+    &mut action,
+    &mut command,
+    platform.clone(),
+    &parse_semver("2.2").unwrap(),
+  );
+  assert_eq!(action.platform, Some(platform));
+  assert_eq!(command.platform, None);
+}
+
+#[test]
+fn attach_platform_to_command() {
+  let platform = platform(&[("OSFamily", "linux")]);
+  let mut action = remexec::Action {
+    platform: Some(remexec::Platform::default()),
+    ..remexec::Action::default()
+  };
+  let mut command = remexec::Command::default();
+  attach_platform(
+    &mut action,
+    &mut command,
+    platform.clone(),
+    &parse_semver("2.1.5").unwrap(),
+  );
+  assert_eq!(action.platform, None);
+  assert_eq!(command.platform, Some(platform));
+}
+
+#[test]
+fn remote_execution_available_by_capabilities() {
+  let caps = remexec::ServerCapabilities {
+    execution_capabilities: Some(remexec::ExecutionCapabilities {
+      digest_function: remexec::digest_function::Value::Sha256 as i32,
+      exec_enabled: true,
+      ..remexec::ExecutionCapabilities::default()
+    }),
+    ..remexec::ServerCapabilities::default()
+  };
+  assert!(remote_execution_available(&caps));
+  assert_eq!(
+    digest_function_from_i32(caps.execution_capabilities.unwrap().digest_function),
+    remexec::digest_function::Value::Sha256
+  );
+
+  let disabled = remexec::ServerCapabilities {
+    execution_capabilities: Some(remexec::ExecutionCapabilities::default()),
+    ..remexec::ServerCapabilities::default()
+  };
+  assert!(!remote_execution_available(&disabled));
+  assert!(!remote_execution_available(
+    &remexec::ServerCapabilities::default()
+  ));
+}
+
+#[test]
+fn upload_strategy_small_blob_batches() {
+  let caps = remexec::CacheCapabilities {
+    max_batch_total_size_bytes: 1024,
+    ..remexec::CacheCapabilities::default()
+  };
+  assert_eq!(upload_strategy(&caps, 10), UploadStrategy::Batch);
+  assert_eq!(upload_strategy(&caps, 0), UploadStrategy::Inline);
+}
+
+#[test]
+fn upload_strategy_large_blob_streams() {
+  let caps = remexec::CacheCapabilities {
+    max_batch_total_size_bytes: 1024,
+    ..remexec::CacheCapabilities::default()
+  };
+  assert_eq!(upload_strategy(&caps, 1024), UploadStrategy::Stream);
+  assert_eq!(upload_strategy(&caps, 4096), UploadStrategy::Stream);
+}
+
+#[test]
+fn upload_strategy_default_caps() {
+  // A limit of 0 means that the server sets no limit.
+  let caps = remexec::CacheCapabilities::default();
+  assert_eq!(upload_strategy(&caps, 1 << 30), UploadStrategy::Batch);
+  assert_eq!(upload_strategy(&caps, 0), UploadStrategy::Inline);
+}
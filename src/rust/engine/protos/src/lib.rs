@@ -4,7 +4,20 @@
 #![deny(unused_must_use)]
 
 mod conversions;
-pub use conversions::require_digest;
+pub use conversions::{
+  action_cache_writable, api_version_supported, attach_platform, batch_read_request,
+  clamp_priority, decode_precondition_failure, dedup_digests_to_prost, digest_function_from_i32,
+  digest_to_event_digest, digest_to_resource_name, digests_to_hashing, digests_to_hashing_from,
+  event_digest_to_digest, execute_response_failure_message, execution_stage_from_i32,
+  find_missing_request, find_missing_response_digests, operation_update, parse_batch_read_response,
+  parse_batch_update_response, parse_operation_name_digest, parse_semver, platform_cache_key,
+  read_request, remote_execution_available, request_metadata_from_header,
+  request_metadata_to_header, require_digest, resource_name_digest,
+  resource_name_digest_and_function, sanitize_unix_mode, snapshot_root_to_directory_digest,
+  stage_label, std_duration_from_prost, symlink_absolute_path_strategy_from_i32,
+  symlinks_absolute_allowed, tree_referenced_digests, upload_strategy, write_request,
+  BatchReadEntry, OperationOutcome, OperationUpdate, UploadStrategy, REQUEST_METADATA_HEADER,
+};
 
 #[cfg(test)]
 mod conversions_tests;
@@ -48,7 +48,7 @@ use crate::externs::fs::PyFileDigest;
 use crate::{
   externs, nodes, Context, Core, ExecutionRequest, ExecutionStrategyOptions, ExecutionTermination,
   Failure, Function, Intrinsic, Intrinsics, Key, LocalStoreOptions, Params, RemotingOptions, Rule,
-  Scheduler, Session, Tasks, TypeId, Types, Value,
+  Scheduler, Session, StragglerLogFormat, Tasks, TypeId, Types, Value,
 };
 
 #[pymodule]
@@ -359,7 +359,7 @@ impl PySession {
     let cancellation_latch = cancellation_latch.0.clone();
     // NB: Session creation interacts with the Graph, which must not be accessed while the GIL is
     // held.
-    let session = py
+    let (session, _run_id) = py
       .allow_threads(|| {
         Session::new(
           core,
@@ -367,6 +367,7 @@ impl PySession {
           build_id,
           session_values,
           cancellation_latch,
+          StragglerLogFormat::default(),
         )
       })
       .map_err(PyException::new_err)?;
@@ -52,6 +52,9 @@ pub use crate::context::{
 pub use crate::intrinsics::Intrinsics;
 pub use crate::python::{Failure, Function, Key, Params, TypeId, Value};
 pub use crate::scheduler::{ExecutionRequest, ExecutionTermination, Scheduler};
-pub use crate::session::Session;
+pub use crate::session::{
+  BoundError, CancelGuard, MetricsSnapshot, Session, SessionDisplayMode, SessionSummary,
+  SessionsHealth, StragglerLogFormat,
+};
 pub use crate::tasks::{Intrinsic, Rule, Tasks};
 pub use crate::types::Types;
@@ -419,7 +419,7 @@ impl WrappedNode for ExecuteProcess {
 
     let execution_context = process_execution::Context::new(
       context.session.workunit_store(),
-      context.session.build_id().to_string(),
+      context.session.build_id(),
       context.session.run_id(),
     );
 
@@ -118,26 +118,7 @@ impl Scheduler {
   /// Return Scheduler and per-Session metrics.
   ///
   pub fn metrics(&self, session: &Session) -> HashMap<&str, i64> {
-    let context = Context::new(self.core.clone(), session.clone());
-    let mut m = HashMap::new();
-    m.insert("affected_file_count", {
-      let mut count = 0;
-      self
-        .core
-        .graph
-        .visit_live_reachable(&session.roots_nodes(), &context, |n, _| {
-          if n.fs_subject().is_some() {
-            count += 1;
-          }
-        });
-      count
-    });
-    m.insert(
-      "preceding_graph_size",
-      session.preceding_graph_size() as i64,
-    );
-    m.insert("resulting_graph_size", self.core.graph.len() as i64);
-    m
+    session.metrics()
   }
 
   ///
@@ -203,6 +184,7 @@ impl Scheduler {
     request: &ExecutionRequest,
     session: &Session,
   ) -> Vec<ObservedValueResult> {
+    session.new_run_id_if_uncacheable();
     let context = Context::new(session.core().clone(), session.clone());
     let roots = session.roots_zip_last_observed(&request.roots);
     let poll = request.poll;
@@ -261,6 +243,9 @@ impl Scheduler {
     let interval = ConsoleUI::render_interval();
     let deadline = request.timeout.map(|timeout| Instant::now() + timeout);
     let executor = self.core.executor.clone();
+    let _work = session
+      .track_request()
+      .map_err(ExecutionTermination::Fatal)?;
 
     // Spawn and wait for all roots to complete.
     self.core.executor.block_on(async move {
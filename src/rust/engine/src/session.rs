@@ -1,17 +1,18 @@
 // Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::future::Future;
-use std::sync::atomic::{self, AtomicU32};
+use std::sync::atomic::{self, AtomicBool, AtomicU32, AtomicU64, AtomicUsize};
 use std::sync::{Arc, Weak};
 use std::time::{Duration, Instant};
 
-use crate::context::Core;
+use crate::context::{Context, Core};
 use crate::nodes::{NodeKey, Select};
 use crate::python::{Failure, Value};
 
 use async_latch::AsyncLatch;
+use async_trait::async_trait;
 use futures::future::{self, AbortHandle, Abortable};
 use futures::FutureExt;
 use graph::LastObserved;
@@ -19,20 +20,92 @@ use log::warn;
 use parking_lot::{Mutex, RwLock};
 use pyo3::prelude::*;
 use task_executor::Executor;
-use tokio::signal::unix::{signal, SignalKind};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
 use ui::ConsoleUI;
-use workunit_store::{format_workunit_duration_ms, RunId, UserMetadataPyValue, WorkunitStore};
+use workunit_store::{
+  format_workunit_duration_ms, RunId, UserMetadataPyValue, WorkunitStore, WorkunitTreeNode,
+};
 
 // When enabled, the interval at which all stragglers that have been running for longer than a
 // threshold should be logged. The threshold might become configurable, but this might not need
 // to be.
 const STRAGGLER_LOGGING_INTERVAL: Duration = Duration::from_secs(30);
 
+// While the set of stragglers remains substantially the same, the interval between straggler logs
+// doubles (up to this maximum) to avoid burying other logs.
+const STRAGGLER_LOGGING_MAX_INTERVAL: Duration = Duration::from_secs(300);
+
+// The default maximum number of characters of a straggling workunit's description to log (the
+// remainder is replaced by an ellipsis).
+const STRAGGLER_MAX_DESCRIPTION_LENGTH: usize = 200;
+
 // Root requests are limited to Select nodes, which produce (python) Values.
 pub type Root = Select;
 
 pub type ObservedValueResult = Result<(Value, Option<LastObserved>), Failure>;
 
+///
+/// The format used to log stragglers when the dynamic UI is disabled.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StragglerLogFormat {
+  /// A human-readable multi-line message.
+  Human,
+  /// A single-line JSON object, for consumption by CI systems.
+  Json,
+}
+
+impl Default for StragglerLogFormat {
+  fn default() -> Self {
+    StragglerLogFormat::Human
+  }
+}
+
+impl StragglerLogFormat {
+  fn format(&self, stragglers: Vec<(Duration, String)>, max_description_length: usize) -> String {
+    let stragglers = stragglers
+      .into_iter()
+      .map(|(duration, desc)| (duration, truncate_description(desc, max_description_length)));
+    match self {
+      StragglerLogFormat::Human => format!(
+        "Long running tasks:\n  {}",
+        stragglers
+          .map(|(duration, desc)| format!(
+            "{}\t{}",
+            format_workunit_duration_ms!(duration.as_millis()),
+            desc
+          ))
+          .collect::<Vec<_>>()
+          .join("\n  ")
+      ),
+      StragglerLogFormat::Json => serde_json::json!({
+        "event": "stragglers",
+        "tasks": stragglers
+          .map(|(duration, desc)| serde_json::json!({
+            "duration_ms": duration.as_millis() as u64,
+            "desc": desc,
+          }))
+          .collect::<Vec<_>>(),
+      })
+      .to_string(),
+    }
+  }
+}
+
+///
+/// Truncates the given description to at most `max_length` characters, followed by an ellipsis.
+///
+fn truncate_description(desc: String, max_length: usize) -> String {
+  if desc.chars().count() > max_length {
+    let mut truncated = desc.chars().take(max_length).collect::<String>();
+    truncated.push_str("...");
+    truncated
+  } else {
+    desc
+  }
+}
+
 ///
 /// An enum for the two cases of `--[no-]dynamic-ui`.
 ///
@@ -43,14 +116,40 @@ enum SessionDisplay {
   Logging {
     straggler_threshold: Duration,
     straggler_deadline: Option<Instant>,
+    // The delay before the first check for stragglers: subsequent checks occur every
+    // `straggler_interval`.
+    initial_straggler_delay: Duration,
+    // The current interval between checks for stragglers, which backs off while the set of
+    // stragglers (the descriptions of which were last logged in `previous_stragglers`) is stable.
+    straggler_interval: Duration,
+    previous_stragglers: HashSet<String>,
+    straggler_format: StragglerLogFormat,
+    // The maximum length of a straggler description in logs: `straggling_workunits` continues to
+    // return the complete descriptions.
+    straggler_max_description_length: usize,
   },
 }
 
+///
+/// The display mode of a Session: see `Session::display_mode`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionDisplayMode {
+  /// The dynamic UI is enabled.
+  ConsoleUI,
+  /// The dynamic UI is disabled, and stragglers are logged once `armed` (i.e., once the display has
+  /// been initialized, and until it is torn down).
+  Logging { armed: bool },
+  /// The display was in use, so its mode could not be determined.
+  Unknown,
+}
+
 impl SessionDisplay {
   fn new(
     workunit_store: &WorkunitStore,
     parallelism: usize,
     should_render_ui: bool,
+    straggler_format: StragglerLogFormat,
   ) -> SessionDisplay {
     if should_render_ui {
       SessionDisplay::ConsoleUI(ConsoleUI::new(workunit_store.clone(), parallelism))
@@ -60,11 +159,106 @@ impl SessionDisplay {
         // probably does not need to be.
         straggler_threshold: Duration::from_secs(60),
         straggler_deadline: None,
+        initial_straggler_delay: STRAGGLER_LOGGING_INTERVAL,
+        straggler_interval: STRAGGLER_LOGGING_INTERVAL,
+        previous_stragglers: HashSet::new(),
+        straggler_format,
+        straggler_max_description_length: STRAGGLER_MAX_DESCRIPTION_LENGTH,
+      }
+    }
+  }
+
+  fn mode(&self) -> SessionDisplayMode {
+    match *self {
+      SessionDisplay::ConsoleUI(_) => SessionDisplayMode::ConsoleUI,
+      SessionDisplay::Logging {
+        straggler_deadline, ..
+      } => SessionDisplayMode::Logging {
+        armed: straggler_deadline.is_some(),
+      },
+    }
+  }
+
+  ///
+  /// Renders the ConsoleUI, or logs straggling workunits if the next check is due (or `force` is
+  /// set).
+  ///
+  fn render(&mut self, workunit_store: &WorkunitStore, force: bool) {
+    match *self {
+      SessionDisplay::ConsoleUI(ref mut ui) => ui.render(),
+      SessionDisplay::Logging {
+        straggler_threshold,
+        ref mut straggler_deadline,
+        ref mut straggler_interval,
+        ref mut previous_stragglers,
+        straggler_format,
+        straggler_max_description_length,
+        ..
+      } => {
+        let now = Instant::now();
+        let due = straggler_deadline.map(|sd| sd < now).unwrap_or(false);
+        if !due && !force {
+          return;
+        }
+        let straggling_workunits = workunit_store.straggling_workunits(straggler_threshold);
+        let stragglers = straggling_workunits
+          .iter()
+          .map(|(_, desc)| desc.clone())
+          .collect::<HashSet<_>>();
+        *straggler_interval =
+          next_straggler_interval(*straggler_interval, previous_stragglers, &stragglers);
+        *previous_stragglers = stragglers;
+        if let Some(deadline) = straggler_deadline.as_mut() {
+          *deadline = now + *straggler_interval;
+        }
+        if !straggling_workunits.is_empty() {
+          log::info!(
+            "{}",
+            straggler_format.format(straggling_workunits, straggler_max_description_length)
+          );
+        }
+      }
+    }
+  }
+
+  fn initialize(&mut self, executor: &Executor) -> Result<(), String> {
+    match *self {
+      SessionDisplay::ConsoleUI(ref mut ui) => ui.initialize(executor.clone()),
+      SessionDisplay::Logging {
+        ref mut straggler_deadline,
+        initial_straggler_delay,
+        ref mut straggler_interval,
+        ref mut previous_stragglers,
+        ..
+      } => {
+        *straggler_deadline = Some(Instant::now() + initial_straggler_delay);
+        *straggler_interval = STRAGGLER_LOGGING_INTERVAL;
+        previous_stragglers.clear();
+        Ok(())
       }
     }
   }
 }
 
+///
+/// Computes the interval until the next check for stragglers: if at least 80% of the stragglers
+/// are the same as at the previous check, the interval doubles (up to a maximum), and otherwise it
+/// resets to `STRAGGLER_LOGGING_INTERVAL`.
+///
+fn next_straggler_interval(
+  interval: Duration,
+  previous_stragglers: &HashSet<String>,
+  stragglers: &HashSet<String>,
+) -> Duration {
+  let shared = previous_stragglers.intersection(stragglers).count();
+  let total = previous_stragglers.union(stragglers).count();
+  if !stragglers.is_empty() && shared * 5 >= total * 4 {
+    std::cmp::min(interval * 2, STRAGGLER_LOGGING_MAX_INTERVAL)
+  } else {
+    STRAGGLER_LOGGING_INTERVAL
+  }
+}
+
 ///
 /// The portion of a Session that uniquely identifies it and holds metrics and the history of
 /// requests made on it.
@@ -74,249 +268,1617 @@ struct SessionState {
   core: Arc<Core>,
   // The total size of the graph at Session-creation time.
   preceding_graph_size: usize,
-  // The set of roots that have been requested within this session, with associated LastObserved
-  // times if they were polled.
-  roots: Mutex<HashMap<Root, Option<LastObserved>>>,
+  // The time at which this Session was created.
+  start_time: Instant,
+  // The roots, run_id, counters and other state which changes as requests are made on this Session.
+  requests: RequestState<Root, PyObject, Value>,
   // A place to store info about workunits in rust part
   workunit_store: WorkunitStore,
   // Per-Session values that have been set for this session.
-  session_values: Mutex<PyObject>,
+  session_values: SessionValues<PyObject>,
+  // Callbacks to invoke with each new `run_id`.
+  run_id_callbacks: RunIdCallbacks,
+}
+
+///
+/// The portion of a SessionState which changes as roots are requested on a Session.
+///
+/// Generic over the types of roots, of workunit metadata values, of root metadata values and of
+/// observations (`Root`, `PyObject`, `Value` and `LastObserved` for a Session) so that it can be
+/// tested without a Python interpreter or a Core.
+///
+struct RequestState<R, V, M, O = LastObserved> {
+  // The set of roots that have been requested within this session, with associated LastObserved
+  // times if they were polled.
+  roots: Mutex<HashMap<R, Option<O>>>,
+  // Experimental metadata associated with requested roots. Cleared for each new `run_id`.
+  root_metadata: Mutex<HashMap<R, M>>,
+  // The time at which the current `run_id` was started: either when this RequestState was created,
+  // or at the most recent `start_run`.
+  iteration_start_time: Mutex<Instant>,
   // An id used to control the visibility of uncacheable rules. Generally this is identical for an
   // entire Session, but in some cases (in particular, a `--loop`) the caller wants to retain the
   // same Session while still observing new values for uncacheable rules like Goals.
   run_id: AtomicU32,
-  workunit_metadata_map: RwLock<HashMap<UserMetadataPyValue, PyObject>>,
+  // When set, every root request made on this Session observes a fresh `run_id`, which causes all
+  // rules to behave as if they were uncacheable.
+  force_uncacheable: AtomicBool,
+  // Set when the first root is requested on this Session, to log that lifecycle transition once.
+  first_root_requested: AtomicBool,
+  // The number of batches of roots which have been executed (or polled) on this Session, including
+  // repeated requests for the same roots.
+  request_count: AtomicU64,
+  workunit_metadata_map: RwLock<HashMap<UserMetadataPyValue, V>>,
+  // Arbitrary key/value labels for this Session, used to group Sessions in metrics.
+  labels: RwLock<HashMap<String, String>>,
+}
+
+impl<R: Clone + Eq + std::hash::Hash, V, M: Clone, O: Copy> RequestState<R, V, M, O> {
+  fn new(run_id: RunId) -> Self {
+    RequestState {
+      roots: Mutex::new(HashMap::new()),
+      root_metadata: Mutex::new(HashMap::new()),
+      iteration_start_time: Mutex::new(Instant::now()),
+      run_id: AtomicU32::new(run_id.0),
+      force_uncacheable: AtomicBool::new(false),
+      first_root_requested: AtomicBool::new(false),
+      request_count: AtomicU64::new(0),
+      workunit_metadata_map: RwLock::new(HashMap::new()),
+      labels: RwLock::new(HashMap::new()),
+    }
+  }
+
+  fn run_id(&self) -> RunId {
+    RunId(self.run_id.load(atomic::Ordering::SeqCst))
+  }
+
+  ///
+  /// Records a batch of requested roots (and their LastObserved values). A non-empty batch counts
+  /// as a request: returns true if it was the first request.
+  ///
+  fn record_request(&self, new_roots: Vec<(R, Option<O>)>) -> bool {
+    let first_request = if new_roots.is_empty() {
+      false
+    } else {
+      self.request_count.fetch_add(1, atomic::Ordering::SeqCst);
+      !self
+        .first_root_requested
+        .swap(true, atomic::Ordering::SeqCst)
+    };
+    self.roots.lock().extend(new_roots);
+    first_request
+  }
+
+  fn request_count(&self) -> u64 {
+    self.request_count.load(atomic::Ordering::SeqCst)
+  }
+
+  ///
+  /// Returns the observation for a single root, if it has been polled.
+  ///
+  fn last_observed(&self, root: &R) -> Option<O> {
+    self.roots.lock().get(root).and_then(|lo| *lo)
+  }
+
+  ///
+  /// Polls the given root with its previous observation (if any) using `poll`, and on success
+  /// records the new observation for the next poll. Returns the result of `poll`, and whether this
+  /// was the first request (see `record_request`).
+  ///
+  async fn poll<T, E, F>(
+    &self,
+    root: R,
+    poll: impl FnOnce(Option<O>) -> F,
+  ) -> Result<(T, O, bool), E>
+  where
+    F: Future<Output = Result<(T, O), E>>,
+  {
+    let (result, last_observed) = poll(self.last_observed(&root)).await?;
+    let first_request = self.record_request(vec![(root, Some(last_observed))]);
+    Ok((result, last_observed, first_request))
+  }
+
+  ///
+  /// Pairs each of the given roots with its observation, if it has been polled.
+  ///
+  fn zip_last_observed(&self, inputs: &[R]) -> Vec<(R, Option<O>)> {
+    let roots = self.roots.lock();
+    inputs
+      .iter()
+      .map(|root| (root.clone(), roots.get(root).and_then(|lo| *lo)))
+      .collect()
+  }
+
+  fn set_label(&self, key: String, value: String) {
+    self.labels.write().insert(key, value);
+  }
+
+  fn labels(&self) -> HashMap<String, String> {
+    self.labels.read().clone()
+  }
+
+  ///
+  /// Returns the sorted descriptions of the requested roots.
+  ///
+  /// NB: Describing a root may acquire the GIL, so the roots are cloned out of the lock first.
+  ///
+  fn root_descriptions(&self, describe: impl Fn(&R) -> String) -> Vec<String> {
+    let roots = self.roots.lock().keys().cloned().collect::<Vec<_>>();
+    let mut descriptions = roots.iter().map(describe).collect::<Vec<_>>();
+    descriptions.sort();
+    descriptions
+  }
+
+  fn set_root_metadata(&self, root: R, value: M) {
+    self.root_metadata.lock().insert(root, value);
+  }
+
+  fn root_metadata(&self, root: &R) -> Option<M> {
+    self.root_metadata.lock().get(root).cloned()
+  }
+
+  ///
+  /// Clears the roots, root and workunit metadata, labels and counters (but not the `run_id` or
+  /// `force_uncacheable`). See `Session::reset`.
+  ///
+  /// NB: The removed workunit metadata is returned so that the caller can drop it after the lock
+  /// has been released, since dropping it might run Python code.
+  ///
+  fn reset(&self) -> HashMap<UserMetadataPyValue, V> {
+    self.roots.lock().clear();
+    self.root_metadata.lock().clear();
+    let metadata = std::mem::take(&mut *self.workunit_metadata_map.write());
+    self.labels.write().clear();
+    self.request_count.store(0, atomic::Ordering::SeqCst);
+    self
+      .first_root_requested
+      .store(false, atomic::Ordering::SeqCst);
+    metadata
+  }
+
+  ///
+  /// Starts a new run by replacing the current `run_id` (returning the previous value), clearing
+  /// the root metadata of the previous run, and restarting the iteration timer.
+  ///
+  fn start_run(&self, run_id: RunId) -> RunId {
+    let previous_run_id = RunId(self.run_id.swap(run_id.0, atomic::Ordering::SeqCst));
+    self.root_metadata.lock().clear();
+    *self.iteration_start_time.lock() = Instant::now();
+    previous_run_id
+  }
+
+  fn iteration_elapsed(&self) -> Duration {
+    self.iteration_start_time.lock().elapsed()
+  }
+
+  ///
+  /// If `force_uncacheable` is set, replaces the current `run_id` with one created by `generate`,
+  /// and returns the previous and new values. Called before each request.
+  ///
+  fn force_new_run_id(&self, generate: impl FnOnce() -> RunId) -> Option<(RunId, RunId)> {
+    if !self.force_uncacheable.load(atomic::Ordering::SeqCst) {
+      return None;
+    }
+    let run_id = generate();
+    Some((self.start_run(run_id), run_id))
+  }
 }
 
 ///
 /// A cancellable handle to a Session, with an optional associated UI.
 ///
 struct SessionHandle {
-  // The unique id for this Session: used for metrics gathering purposes.
-  build_id: String,
+  // The unique id for this Session: used for metrics gathering purposes. May be replaced exactly
+  // once (see `Session::set_build_id`), which is recorded by `build_id_finalized`.
+  build_id: Arc<RwLock<String>>,
+  build_id_finalized: AtomicBool,
   // Whether or not this Session has been cancelled. If a Session has been cancelled, all work that
   // it started should attempt to exit in an orderly fashion.
   cancelled: AsyncLatch,
+  // Set when the work of this Session finished normally: distinguishes a Session which completed
+  // (and was then dropped, which triggers `cancelled`) from one which was aborted.
+  completed: Arc<AtomicBool>,
   // True if this Session should be shielded from keyboard interrupts (which cancel all
   // non-isolated Sessions).
   isolated: bool,
   // The display mechanism to use in this Session.
   display: tokio::sync::Mutex<SessionDisplay>,
+  // The cumulative time spent waiting to acquire the `display` lock, in nanoseconds.
+  display_lock_wait_nanos: AtomicU64,
+  // Triggered when teardown of the display has completed.
+  teardown_complete: AsyncLatch,
+  // The number of requests which are currently running on this handle, and a notification for
+  // when that count drops to zero: used to wait for work to wind down after cancellation.
+  in_flight: AtomicUsize,
+  idle: Notify,
+  // Set once this handle should accept no new requests: see `Session::begin_drain`.
+  draining: AtomicBool,
+  // For isolated clones, a permit which bounds the number of concurrent background Sessions (if
+  // `Sessions` was configured with a limit). Released when this handle is dropped.
+  _background_permit: Option<OwnedSemaphorePermit>,
+  // The isolated clones which were created from this handle: see `Session::cancel_children`.
+  children: Mutex<Vec<Weak<SessionHandle>>>,
+  // If set, the time at which this handle will be automatically cancelled. See
+  // `Session::set_deadline`.
+  deadline: Arc<Deadline>,
 }
 
 impl SessionHandle {
+  fn new(
+    build_id: String,
+    cancelled: AsyncLatch,
+    isolated: bool,
+    display: SessionDisplay,
+    background_permit: Option<OwnedSemaphorePermit>,
+  ) -> SessionHandle {
+    SessionHandle {
+      build_id: Arc::new(RwLock::new(build_id)),
+      build_id_finalized: AtomicBool::new(false),
+      cancelled,
+      completed: Arc::new(AtomicBool::new(false)),
+      isolated,
+      display: tokio::sync::Mutex::new(display),
+      display_lock_wait_nanos: AtomicU64::new(0),
+      teardown_complete: AsyncLatch::new(),
+      in_flight: AtomicUsize::new(0),
+      idle: Notify::new(),
+      draining: AtomicBool::new(false),
+      _background_permit: background_permit,
+      children: Mutex::new(Vec::new()),
+      deadline: Arc::new(Deadline::default()),
+    }
+  }
+
+  fn build_id(&self) -> String {
+    self.build_id.read().clone()
+  }
+
+  fn set_build_id(&self, build_id: String) -> Result<(), String> {
+    if self.build_id_finalized.swap(true, atomic::Ordering::SeqCst) {
+      return Err(format!(
+        "Cannot set the build_id of Session `{}` to `{}`: it has already been set.",
+        self.build_id(),
+        build_id
+      ));
+    }
+    *self.build_id.write() = build_id;
+    Ok(())
+  }
+
+  fn log_created(&self, run_id: RunId) {
+    log::debug!(
+      "Session `{}` created with run_id {}.",
+      self.build_id(),
+      run_id.0
+    );
+  }
+
+  fn log_first_root_requested(&self) {
+    log::debug!("Session `{}` requested its first root.", self.build_id());
+  }
+
+  fn log_started_run_id(&self, run_id: RunId) {
+    log::debug!("Session `{}` started run_id {}.", self.build_id(), run_id.0);
+  }
+
   ///
   /// Cancels this Session.
   ///
   pub fn cancel(&self) {
+    log::debug!("Session `{}` cancelled.", self.build_id());
     self.cancelled.trigger();
   }
-}
 
-impl Drop for SessionHandle {
-  fn drop(&mut self) {
-    self.cancelled.trigger();
+  async fn display_teardown(&self) {
+    let teardown = match *self.lock_display().await {
+      SessionDisplay::ConsoleUI(ref mut ui) => ui.teardown().boxed(),
+      SessionDisplay::Logging {
+        ref mut straggler_deadline,
+        ..
+      } => {
+        *straggler_deadline = None;
+        async { Ok(()) }.boxed()
+      }
+    };
+    if let Err(e) = teardown.await {
+      warn!("{}", e);
+    }
+    self.teardown_complete.trigger();
   }
-}
 
-///
-/// A Session represents a related series of requests (generally: one run of the pants CLI) on an
-/// underlying Scheduler, and is a useful scope for metrics.
-///
-/// Both Scheduler and Session are exposed to python and expected to be used by multiple threads, so
-/// they use internal mutability in order to avoid exposing locks to callers.
-///
-/// NB: The `SessionState` and `SessionHandle` structs are independent in order to allow for a
-/// shallow clone of a Session with independent cancellation and display properties, but which
-/// shares the same metrics and identity.
-///
-#[derive(Clone)]
-pub struct Session {
-  handle: Arc<SessionHandle>,
-  state: Arc<SessionState>,
-}
+  ///
+  /// Acquires the display lock, recording the time spent waiting for it.
+  ///
+  async fn lock_display(&self) -> tokio::sync::MutexGuard<'_, SessionDisplay> {
+    let start = Instant::now();
+    let display = self.display.lock().await;
+    self
+      .display_lock_wait_nanos
+      .fetch_add(start.elapsed().as_nanos() as u64, atomic::Ordering::Relaxed);
+    display
+  }
 
-impl Session {
-  pub fn new(
-    core: Arc<Core>,
-    should_render_ui: bool,
-    build_id: String,
-    session_values: PyObject,
-    cancelled: AsyncLatch,
-  ) -> Result<Session, String> {
-    let workunit_store = WorkunitStore::new(!should_render_ui);
-    let display = tokio::sync::Mutex::new(SessionDisplay::new(
-      &workunit_store,
-      core.local_parallelism,
-      should_render_ui,
-    ));
+  fn display_lock_wait(&self) -> Duration {
+    Duration::from_nanos(self.display_lock_wait_nanos.load(atomic::Ordering::Relaxed))
+  }
 
-    let handle = Arc::new(SessionHandle {
-      build_id,
-      cancelled,
-      isolated: false,
-      display,
+  ///
+  /// Calls the given function with the final `build_id` of this handle, and whether it was marked
+  /// completed, once it has been cancelled (including by being dropped).
+  ///
+  /// NB: The build_id and completion flag are shared with the spawned task, which does not hold a
+  /// reference to this handle, so that it does not keep it alive.
+  ///
+  fn on_end(&self, executor: &Executor, f: impl FnOnce(String, bool) + Send + 'static) {
+    let cancelled = self.cancelled.clone();
+    let build_id = self.build_id.clone();
+    let completed = self.completed.clone();
+    let _ = executor.spawn(async move {
+      cancelled.triggered().await;
+      let build_id = build_id.read().clone();
+      f(build_id, completed.load(atomic::Ordering::SeqCst));
     });
-    core.sessions.add(&handle)?;
-    let run_id = core.sessions.generate_run_id();
-    let preceding_graph_size = core.graph.len();
-    Ok(Session {
-      handle,
-      state: Arc::new(SessionState {
-        core,
-        preceding_graph_size,
-        roots: Mutex::new(HashMap::new()),
-        workunit_store,
-        session_values: Mutex::new(session_values),
-        run_id: AtomicU32::new(run_id.0),
-        workunit_metadata_map: RwLock::new(HashMap::new()),
-      }),
-    })
+  }
+
+  fn mark_completed(&self) {
+    self.completed.store(true, atomic::Ordering::SeqCst);
+  }
+
+  fn is_completed(&self) -> bool {
+    self.completed.load(atomic::Ordering::SeqCst)
+  }
+
+  fn cancellation_token(&self) -> CancellationToken {
+    CancellationToken(self.cancelled.clone())
+  }
+
+  fn cancellation_latch(&self) -> AsyncLatch {
+    self.cancelled.clone()
+  }
+
+  fn add_child(&self, child: &Arc<SessionHandle>) {
+    let mut children = self.children.lock();
+    children.retain(|weak_handle| weak_handle.upgrade().is_some());
+    children.push(Arc::downgrade(child));
   }
 
   ///
-  /// Creates a shallow clone of this Session which is independently cancellable, but which shares
-  /// metrics, identity, and state with the original.
+  /// Runs the given Future, and then cancels this handle, so that nothing which retained a
+  /// reference to it can continue to run work. See `Session::with_isolated_scope`.
   ///
-  /// Useful when executing background work "on behalf of a Session" which should not be torn down
-  /// when a client disconnects, or killed by Ctrl+C.
+  async fn run_scoped<T>(self: &Arc<Self>, f: impl Future<Output = T>) -> T {
+    let _cancel_guard = CancelGuard(Some(self.clone()));
+    f.await
+  }
+
   ///
-  pub fn isolated_shallow_clone(&self, build_id: String) -> Result<Session, String> {
-    let display = tokio::sync::Mutex::new(SessionDisplay::new(
-      &self.state.workunit_store,
-      self.state.core.local_parallelism,
-      false,
+  /// Cancels all live isolated clones of this handle, but not this handle itself.
+  ///
+  fn cancel_children(&self) {
+    let children = self
+      .children
+      .lock()
+      .iter()
+      .flat_map(|child| child.upgrade())
+      .collect::<Vec<_>>();
+    for child in children {
+      child.cancel();
+    }
+  }
+
+  ///
+  /// Spawns the given Future on the given Executor, and aborts it when this handle is cancelled
+  /// (or when the returned AbortHandle is used).
+  ///
+  fn spawn_tracked<F>(&self, executor: &Executor, f: F) -> AbortHandle
+  where
+    F: Future<Output = ()> + Send + 'static,
+  {
+    let (abort_handle, abort_registration) = AbortHandle::new_pair();
+    let cancelled = self.cancelled.clone();
+    let _ = executor.spawn(Abortable::new(
+      async move {
+        tokio::select! {
+          _ = f => {}
+          _ = cancelled.triggered() => {}
+        }
+      },
+      abort_registration,
     ));
-    let handle = Arc::new(SessionHandle {
-      build_id,
-      isolated: true,
-      cancelled: AsyncLatch::new(),
-      display,
+    abort_handle
+  }
+
+  ///
+  /// Sets a deadline `timeout` from now at which this handle will be cancelled, replacing any
+  /// previous deadline.
+  ///
+  fn set_deadline(&self, executor: &Executor, timeout: Duration) {
+    let previous = self.deadline.at.lock().replace(Instant::now() + timeout);
+    if previous.is_some() {
+      // A watcher is already running: wake it to observe the new deadline.
+      self.deadline.changed.notify_waiters();
+      return;
+    }
+
+    // NB: The watcher does not hold a reference to this handle, so that it does not keep it alive
+    // until the deadline. It is aborted if the handle is cancelled (including by being dropped).
+    let deadline = self.deadline.clone();
+    let cancelled = self.cancelled.clone();
+    let build_id = self.build_id();
+    let _ = self.spawn_tracked(executor, async move {
+      loop {
+        // NB: Create the Notified future before reading the deadline, so that a change which
+        // occurs between the read and the await is not missed.
+        let changed = deadline.changed.notified();
+        let at = *deadline.at.lock();
+        let at = if let Some(at) = at {
+          at
+        } else {
+          return;
+        };
+        if at <= Instant::now() {
+          log::debug!("Session `{}` reached its deadline.", build_id);
+          cancelled.trigger();
+          return;
+        }
+        tokio::select! {
+          _ = tokio::time::sleep_until(at.into()) => {}
+          _ = changed => {}
+        }
+      }
     });
-    self.state.core.sessions.add(&handle)?;
-    Ok(Session {
-      handle,
+  }
+
+  ///
+  /// Moves the deadline of this handle later by `by`, which fails if this handle has already been
+  /// cancelled, or if it has no pending deadline.
+  ///
+  fn extend_deadline(&self, by: Duration) -> Result<(), String> {
+    if self.cancelled.poll_triggered() {
+      return Err(format!(
+        "Cannot extend the deadline of Session `{}`: it has already been cancelled.",
+        self.build_id()
+      ));
+    }
+    {
+      let mut at = self.deadline.at.lock();
+      match *at {
+        Some(deadline) if deadline > Instant::now() => *at = Some(deadline + by),
+        Some(_) => {
+          return Err(format!(
+            "Cannot extend the deadline of Session `{}`: it has already passed.",
+            self.build_id()
+          ))
+        }
+        None => {
+          return Err(format!(
+            "Cannot extend the deadline of Session `{}`: no deadline has been set.",
+            self.build_id()
+          ))
+        }
+      }
+    }
+    self.deadline.changed.notify_waiters();
+    Ok(())
+  }
+
+  fn time_until_deadline(&self) -> Option<Duration> {
+    self
+      .deadline
+      .at
+      .lock()
+      .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+  }
+
+  ///
+  /// Runs the given Future until it completes, this handle is cancelled, or the timeout elapses
+  /// (whichever happens first). A zero timeout is treated as no timeout.
+  ///
+  async fn run_bounded<T>(
+    &self,
+    timeout: Duration,
+    f: impl Future<Output = T>,
+  ) -> Result<T, BoundError> {
+    let timed_out = async {
+      if timeout.is_zero() {
+        future::pending::<()>().await
+      } else {
+        tokio::time::sleep(timeout).await
+      }
+    };
+    tokio::select! {
+      res = f => Ok(res),
+      _ = self.cancelled.triggered() => Err(BoundError::Cancelled),
+      _ = timed_out => Err(BoundError::TimedOut),
+    }
+  }
+
+  ///
+  /// Returns true if this handle's display is (probably) a ConsoleUI. If the display is currently
+  /// locked, assumes that it is a ConsoleUI, since writing directly to the terminal while a
+  /// ConsoleUI owns it would corrupt the UI.
+  ///
+  fn has_console_ui(&self) -> bool {
+    match self.display.try_lock() {
+      Ok(display) => matches!(*display, SessionDisplay::ConsoleUI(_)),
+      Err(_) => true,
+    }
+  }
+
+  fn display_mode(&self) -> SessionDisplayMode {
+    match self.display.try_lock() {
+      Ok(display) => display.mode(),
+      Err(_) => SessionDisplayMode::Unknown,
+    }
+  }
+
+  fn track_work(self: &Arc<Self>) -> WorkGuard {
+    self.in_flight.fetch_add(1, atomic::Ordering::SeqCst);
+    WorkGuard(self.clone())
+  }
+
+  ///
+  /// Tracks a new request on this handle, unless it is draining.
+  ///
+  /// NB: The work is tracked before checking whether the handle is draining, so that a drain which
+  /// begins concurrently either rejects the request, or waits for it to complete.
+  ///
+  fn track_request(self: &Arc<Self>) -> Result<WorkGuard, String> {
+    let work = self.track_work();
+    if self.is_draining() {
+      std::mem::drop(work);
+      return Err(format!(
+        "Session `{}` is draining: no new requests may be made.",
+        self.build_id()
+      ));
+    }
+    Ok(work)
+  }
+
+  ///
+  /// Waits until no tracked work is running on this handle.
+  ///
+  async fn wait_for_idle(&self) {
+    loop {
+      // NB: Create the Notified future before checking the count, so that a notification which
+      // occurs between the check and the await is not missed.
+      let idle = self.idle.notified();
+      if self.in_flight.load(atomic::Ordering::SeqCst) == 0 {
+        break;
+      }
+      idle.await;
+    }
+  }
+
+  fn begin_drain(&self) {
+    if !self.draining.swap(true, atomic::Ordering::SeqCst) {
+      log::debug!("Session `{}` draining.", self.build_id());
+    }
+  }
+
+  fn is_draining(&self) -> bool {
+    self.draining.load(atomic::Ordering::SeqCst)
+  }
+
+  ///
+  /// Cancels this handle, and then waits up to `timeout` for all tracked work to exit.
+  ///
+  async fn cancel_and_wait(&self, timeout: Duration) -> Result<(), String> {
+    self.cancel();
+    tokio::time::timeout(timeout, self.wait_for_idle())
+      .await
+      .map_err(|_| {
+        format!(
+          "Session {} did not finish its work within {:?} of being cancelled.",
+          self.build_id(),
+          timeout
+        )
+      })
+  }
+
+  ///
+  /// Formats a single line describing this handle, given the RunId, elapsed time and labels of the
+  /// SessionState that it is associated with.
+  ///
+  /// NB: The field order (and the order of labels, which are sorted by key) is stable so that logs
+  /// which contain it may be parsed.
+  ///
+  fn describe(&self, run_id: RunId, elapsed: Duration, labels: &HashMap<String, String>) -> String {
+    let labels = labels
+      .iter()
+      .collect::<BTreeMap<_, _>>()
+      .into_iter()
+      .map(|(k, v)| format!("{}={}", k, v))
+      .collect::<Vec<_>>()
+      .join(",");
+    format!(
+      "build_id={} run_id={} isolated={} cancelled={} elapsed_ms={} labels={}",
+      self.build_id(),
+      run_id.0,
+      self.isolated,
+      self.cancelled.poll_triggered(),
+      elapsed.as_millis(),
+      labels
+    )
+  }
+}
+
+impl Drop for SessionHandle {
+  fn drop(&mut self) {
+    log::debug!("Session `{}` dropped.", self.build_id());
+    self.cancelled.trigger();
+  }
+}
+
+///
+/// The deadline at which a SessionHandle will be cancelled, and a notification for when it
+/// changes. See `Session::set_deadline`.
+///
+#[derive(Default)]
+struct Deadline {
+  at: Mutex<Option<Instant>>,
+  changed: Notify,
+}
+
+///
+/// A summary of a Session which has ended (by completing, or by being cancelled or dropped), for
+/// use in metrics. See `Sessions::on_session_end`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionSummary {
+  pub build_id: String,
+  /// The time from the creation of the Session until it ended.
+  pub elapsed: Duration,
+  /// The `run_id` of the Session when it ended.
+  pub run_id: RunId,
+  /// True if the Session was marked completed before it ended: otherwise, it was cancelled.
+  pub completed: bool,
+  /// The change in the size of the graph while the Session was live.
+  pub graph_growth: i64,
+}
+
+type SessionEndCallback = Arc<dyn Fn(SessionSummary) + Send + Sync>;
+
+///
+/// Callbacks to invoke each time a Session ends. See `Sessions::on_session_end`.
+///
+#[derive(Default)]
+struct SessionEndCallbacks(Mutex<Vec<SessionEndCallback>>);
+
+impl SessionEndCallbacks {
+  fn register(&self, callback: SessionEndCallback) {
+    self.0.lock().push(callback);
+  }
+
+  fn notify(&self, summary: SessionSummary) {
+    // NB: The callbacks are cloned out of the lock so that a callback may register another.
+    let callbacks = self.0.lock().clone();
+    for callback in callbacks {
+      callback(summary.clone());
+    }
+  }
+}
+
+///
+/// The per-Session values of a Session, which may be snapshotted and later restored. See
+/// `Session::snapshot_session_values`.
+///
+/// Generic over the type of the values (`PyObject` for a Session) so that it can be tested without
+/// a Python interpreter.
+///
+struct SessionValues<T>(Mutex<T>);
+
+impl<T: Clone> SessionValues<T> {
+  fn new(values: T) -> Self {
+    SessionValues(Mutex::new(values))
+  }
+
+  fn get(&self) -> T {
+    self.0.lock().clone()
+  }
+
+  ///
+  /// Replaces the current values with the given snapshot, returning the previous values.
+  ///
+  /// NB: The previous values are returned so that the caller can drop them after the lock has been
+  /// released, since dropping them might run Python code.
+  ///
+  fn restore(&self, snapshot: T) -> T {
+    std::mem::replace(&mut *self.0.lock(), snapshot)
+  }
+}
+
+type RunIdCallback = Arc<dyn Fn(RunId) + Send + Sync>;
+
+///
+/// Callbacks to invoke each time a Session observes a new `run_id`. See
+/// `Session::on_run_id_change`.
+///
+#[derive(Default)]
+struct RunIdCallbacks(Mutex<Vec<RunIdCallback>>);
+
+impl RunIdCallbacks {
+  fn register(&self, callback: RunIdCallback) {
+    self.0.lock().push(callback);
+  }
+
+  fn notify(&self, run_id: RunId) {
+    // NB: The callbacks are cloned out of the lock so that a callback may register another.
+    let callbacks = self.0.lock().clone();
+    for callback in callbacks {
+      callback(run_id);
+    }
+  }
+}
+
+///
+/// An entry in a workunit metadata map, which is removed when this guard is dropped. See
+/// `Session::with_workunit_metadata`.
+///
+/// NB: Any value which is replaced or removed is dropped only after the lock is released, because
+/// dropping a PyObject may run arbitrary Python code (such as a `__del__` method).
+///
+struct ScopedMetadataEntry<'a, V> {
+  map: &'a RwLock<HashMap<UserMetadataPyValue, V>>,
+  key: UserMetadataPyValue,
+}
+
+impl<'a, V> ScopedMetadataEntry<'a, V> {
+  fn insert(
+    map: &'a RwLock<HashMap<UserMetadataPyValue, V>>,
+    key: UserMetadataPyValue,
+    value: V,
+  ) -> Self {
+    let replaced = map.write().insert(key.clone(), value);
+    std::mem::drop(replaced);
+    ScopedMetadataEntry { map, key }
+  }
+}
+
+impl<'a, V> Drop for ScopedMetadataEntry<'a, V> {
+  fn drop(&mut self) {
+    let removed = self.map.write().remove(&self.key);
+    std::mem::drop(removed);
+  }
+}
+
+///
+/// Marks work as running on a Session until dropped. See `Session::track_work`.
+///
+pub struct WorkGuard(Arc<SessionHandle>);
+
+impl Drop for WorkGuard {
+  fn drop(&mut self) {
+    if self.0.in_flight.fetch_sub(1, atomic::Ordering::SeqCst) == 1 {
+      self.0.idle.notify_waiters();
+    }
+  }
+}
+
+///
+/// Cancels a Session when dropped, unless it has been disarmed. See `Session::cancel_guard`.
+///
+pub struct CancelGuard(Option<Arc<SessionHandle>>);
+
+impl CancelGuard {
+  ///
+  /// Consumes this guard without cancelling the Session.
+  ///
+  pub fn disarm(mut self) {
+    self.0 = None;
+  }
+}
+
+impl Drop for CancelGuard {
+  fn drop(&mut self) {
+    if let Some(handle) = self.0.take() {
+      handle.cancel();
+    }
+  }
+}
+
+///
+/// The reason that a Future run by `Session::run_bounded` did not complete.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BoundError {
+  Cancelled,
+  TimedOut,
+}
+
+///
+/// A cheaply clonable handle which can be used to observe the cancellation of a Session (whether
+/// by a signal, by the Session being dropped, or by an explicit call to `cancel`), but not to
+/// trigger it.
+///
+#[derive(Clone)]
+pub struct CancellationToken(AsyncLatch);
+
+impl CancellationToken {
+  ///
+  /// Returns only once the associated Session has been cancelled.
+  ///
+  pub async fn cancelled(&self) {
+    self.0.triggered().await;
+  }
+
+  ///
+  /// Returns true if the associated Session has been cancelled.
+  ///
+  pub fn is_cancelled(&self) -> bool {
+    self.0.poll_triggered()
+  }
+}
+
+///
+/// A Session represents a related series of requests (generally: one run of the pants CLI) on an
+/// underlying Scheduler, and is a useful scope for metrics.
+///
+/// Both Scheduler and Session are exposed to python and expected to be used by multiple threads, so
+/// they use internal mutability in order to avoid exposing locks to callers.
+///
+/// NB: The `SessionState` and `SessionHandle` structs are independent in order to allow for a
+/// shallow clone of a Session with independent cancellation and display properties, but which
+/// shares the same metrics and identity.
+///
+#[derive(Clone)]
+pub struct Session {
+  handle: Arc<SessionHandle>,
+  state: Arc<SessionState>,
+}
+
+impl Session {
+  ///
+  /// Creates a new Session, returning it along with its initial `run_id` (which may already have
+  /// been changed by the time the caller observes `Session::run_id`).
+  ///
+  pub fn new(
+    core: Arc<Core>,
+    should_render_ui: bool,
+    build_id: String,
+    session_values: PyObject,
+    cancelled: AsyncLatch,
+    straggler_format: StragglerLogFormat,
+  ) -> Result<(Session, RunId), String> {
+    let workunit_store = WorkunitStore::new(!should_render_ui);
+    let display = SessionDisplay::new(
+      &workunit_store,
+      core.local_parallelism,
+      should_render_ui,
+      straggler_format,
+    );
+
+    let handle = Arc::new(SessionHandle::new(
+      build_id, cancelled, false, display, None,
+    ));
+    core.sessions.add(&handle)?;
+    // NB: The initial `run_id` is read back from the RequestState, so that the returned value is
+    // exactly what `Session::run_id` observes before any other thread can replace it.
+    let requests = RequestState::new(core.sessions.generate_run_id());
+    let run_id = requests.run_id();
+    let preceding_graph_size = core.graph.len();
+    let session = Session {
+      handle,
+      state: Arc::new(SessionState {
+        core,
+        preceding_graph_size,
+        start_time: Instant::now(),
+        requests,
+        workunit_store,
+        session_values: SessionValues::new(session_values),
+        run_id_callbacks: RunIdCallbacks::default(),
+      }),
+    };
+    session
+      .state
+      .core
+      .sessions
+      .register_run_id(None, run_id, &session);
+    session.handle.log_created(run_id);
+    session.notify_on_end();
+    Ok((session, run_id))
+  }
+
+  ///
+  /// Creates a shallow clone of this Session which is independently cancellable, but which shares
+  /// metrics, identity, and state with the original.
+  ///
+  /// Useful when executing background work "on behalf of a Session" which should not be torn down
+  /// when a client disconnects, or killed by Ctrl+C.
+  ///
+  /// The clone never renders the dynamic UI, and logs stragglers in the default format.
+  ///
+  /// Fails if `Sessions` was configured with a maximum number of background clones, and that many
+  /// are already live.
+  ///
+  pub fn isolated_shallow_clone(&self, build_id: String) -> Result<Session, String> {
+    let background_permit = self.state.core.sessions.acquire_background_permit()?;
+    let clone = self.shallow_clone_with(build_id, true, background_permit)?;
+    self.handle.add_child(&clone.handle);
+    Ok(clone)
+  }
+
+  ///
+  /// Creates an isolated clone of this Session (see `isolated_shallow_clone`), and runs the Future
+  /// created by `f` on it. The clone is dropped once the Future completes, and is cancelled even if
+  /// `f` retained a reference to it, so that no background work outlives the scope.
+  ///
+  pub async fn with_isolated_scope<F, T>(
+    &self,
+    build_id: String,
+    f: impl FnOnce(Session) -> F,
+  ) -> Result<T, String>
+  where
+    F: Future<Output = T>,
+  {
+    let clone = self.isolated_shallow_clone(build_id)?;
+    let handle = clone.handle.clone();
+    Ok(handle.run_scoped(f(clone)).await)
+  }
+
+  ///
+  /// Creates a shallow clone of this Session which is independently cancellable, but which shares
+  /// metrics, identity, and state with the original. Unlike `isolated_shallow_clone`, the clone is
+  /// cancelled by Ctrl+C along with the original: useful for related foreground requests.
+  ///
+  /// The clone never renders the dynamic UI, and logs stragglers in the default format.
+  ///
+  pub fn shallow_clone(&self, build_id: String) -> Result<Session, String> {
+    self.shallow_clone_with(build_id, false, None)
+  }
+
+  fn shallow_clone_with(
+    &self,
+    build_id: String,
+    isolated: bool,
+    background_permit: Option<OwnedSemaphorePermit>,
+  ) -> Result<Session, String> {
+    let display = SessionDisplay::new(
+      &self.state.workunit_store,
+      self.state.core.local_parallelism,
+      false,
+      StragglerLogFormat::default(),
+    );
+    let handle = Arc::new(SessionHandle::new(
+      build_id,
+      AsyncLatch::new(),
+      isolated,
+      display,
+      background_permit,
+    ));
+    self.state.core.sessions.add(&handle)?;
+    let session = Session {
+      handle,
       state: self.state.clone(),
-    })
+    };
+    self
+      .state
+      .core
+      .sessions
+      .register_run_id(None, session.run_id(), &session);
+    session.notify_on_end();
+    Ok(session)
+  }
+
+  ///
+  /// Arranges for the `Sessions::on_session_end` callbacks to be notified when this Session's
+  /// handle ends.
+  ///
+  fn notify_on_end(&self) {
+    let state = self.state.clone();
+    self
+      .handle
+      .on_end(self.executor(), move |build_id, completed| {
+        let summary = SessionSummary {
+          build_id,
+          elapsed: state.start_time.elapsed(),
+          run_id: state.requests.run_id(),
+          completed,
+          graph_growth: state.core.graph.len() as i64 - state.preceding_graph_size as i64,
+        };
+        state.core.sessions.session_end_callbacks.notify(summary);
+      });
+  }
+
+  pub fn core(&self) -> &Arc<Core> {
+    &self.state.core
+  }
+
+  ///
+  /// The Executor of the Core that this Session is running on, which should be used for any tasks
+  /// spawned on behalf of this Session.
+  ///
+  /// NB: Tasks spawned on the Executor are not automatically cancelled with the Session.
+  ///
+  pub fn executor(&self) -> &Executor {
+    &self.state.core.executor
+  }
+
+  ///
+  /// Spawns the given Future on the Executor of this Session, and aborts it when this Session is
+  /// cancelled: the returned AbortHandle may also be used to abort it earlier. Useful for
+  /// background tasks whose lifetime should be scoped to the Session.
+  ///
+  pub fn spawn_tracked<F>(&self, f: F) -> AbortHandle
+  where
+    F: Future<Output = ()> + Send + 'static,
+  {
+    self.handle.spawn_tracked(self.executor(), f)
+  }
+
+  ///
+  /// Sets a deadline `timeout` from now at which this Session will be cancelled automatically,
+  /// replacing any previous deadline.
+  ///
+  pub fn set_deadline(&self, timeout: Duration) {
+    self.handle.set_deadline(self.executor(), timeout);
+  }
+
+  ///
+  /// Moves the deadline of this Session (see `set_deadline`) later by `by`: e.g. in response to a
+  /// keep-alive from a client. Fails if this Session has already been cancelled, or if it has no
+  /// pending deadline.
+  ///
+  pub fn extend_deadline(&self, by: Duration) -> Result<(), String> {
+    self.handle.extend_deadline(by)
+  }
+
+  ///
+  /// The time remaining until this Session is automatically cancelled (which is zero once the
+  /// deadline has passed), or None if no deadline has been set.
+  ///
+  pub fn time_until_deadline(&self) -> Option<Duration> {
+    self.handle.time_until_deadline()
+  }
+
+  ///
+  /// Cancels this Session.
+  ///
+  pub fn cancel(&self) {
+    self.handle.cancel();
+  }
+
+  ///
+  /// Cancels all live isolated clones which were created from this Session (via
+  /// `isolated_shallow_clone`), while leaving this Session running: e.g. to abandon speculative
+  /// background work.
+  ///
+  pub fn cancel_children(&self) {
+    self.handle.cancel_children();
+  }
+
+  ///
+  /// Marks work as running on behalf of this Session until the returned guard is dropped. Work
+  /// which is tracked this way should exit promptly once the Session has been cancelled.
+  ///
+  pub fn track_work(&self) -> WorkGuard {
+    self.handle.track_work()
+  }
+
+  ///
+  /// Returns a guard which cancels this Session when it is dropped (unless `CancelGuard::disarm` is
+  /// called first), which allows for tying cancellation to a narrower scope than the lifetime of
+  /// the Session and all of its clones.
+  ///
+  pub fn cancel_guard(&self) -> CancelGuard {
+    CancelGuard(Some(self.handle.clone()))
+  }
+
+  ///
+  /// Cancels this Session, and then waits up to `timeout` for all work tracked via `track_work` to
+  /// have observed the cancellation and exited.
+  ///
+  pub async fn cancel_and_wait(&self, timeout: Duration) -> Result<(), String> {
+    self.handle.cancel_and_wait(timeout).await
+  }
+
+  ///
+  /// Stops this Session from accepting new requests, while allowing in-flight requests to
+  /// complete. See `Sessions::drain_and_shutdown`.
+  ///
+  pub fn begin_drain(&self) {
+    self.handle.begin_drain();
+  }
+
+  ///
+  /// Marks a new request as running on this Session until the returned guard is dropped, or fails
+  /// if this Session is draining. See `track_work`.
+  ///
+  pub fn track_request(&self) -> Result<WorkGuard, String> {
+    self.handle.track_request()
+  }
+
+  ///
+  /// Returns true if `begin_drain` has been called on this Session (but not on other clones of it).
+  ///
+  pub fn is_draining(&self) -> bool {
+    self.handle.is_draining()
+  }
+
+  ///
+  /// Returns true if this Session has been cancelled.
+  ///
+  pub fn is_cancelled(&self) -> bool {
+    self.handle.cancelled.poll_triggered()
+  }
+
+  ///
+  /// Marks this Session as having completed its work normally. Should be called before the Session
+  /// is dropped, since dropping a Session also triggers its cancellation.
+  ///
+  pub fn mark_completed(&self) {
+    self.handle.mark_completed();
+  }
+
+  ///
+  /// Returns true if `mark_completed` was called on this Session (but not on other clones of it).
+  ///
+  pub fn is_completed(&self) -> bool {
+    self.handle.is_completed()
+  }
+
+  ///
+  /// Returns only if this Session has been cancelled.
+  ///
+  pub async fn cancelled(&self) {
+    self.handle.cancelled.triggered().await;
+  }
+
+  ///
+  /// Returns a token which may be cloned and awaited independently by multiple subsystems that
+  /// need to observe this Session's cancellation.
+  ///
+  pub fn cancellation_token(&self) -> CancellationToken {
+    self.handle.cancellation_token()
+  }
+
+  ///
+  /// Returns a clone of the latch which was passed to `Session::new`, which shares its state: an
+  /// external subsystem may trigger it to cancel this Session, or await it.
+  ///
+  pub fn cancellation_latch(&self) -> AsyncLatch {
+    self.handle.cancellation_latch()
+  }
+
+  ///
+  /// Runs the given Future until it completes, this Session is cancelled, or the timeout elapses
+  /// (whichever happens first). A zero timeout is treated as no timeout: useful for bounded remote
+  /// calls which should also respect cancellation.
+  ///
+  pub async fn run_bounded<T>(
+    &self,
+    timeout: Duration,
+    f: impl Future<Output = T>,
+  ) -> Result<T, BoundError> {
+    self.handle.run_bounded(timeout, f).await
+  }
+
+  ///
+  /// Runs the given function with the workunit metadata map locked.
+  ///
+  /// NB: The function must not call into Python (including by dropping a PyObject that it removes
+  /// from the map, which might run a `__del__` method): anything it does in Python which requires
+  /// this lock would deadlock. Values should instead be cloned or returned out of the function,
+  /// and then used after the lock has been released.
+  ///
+  pub fn with_metadata_map<F, T>(&self, f: F) -> T
+  where
+    F: FnOnce(&mut HashMap<UserMetadataPyValue, PyObject>) -> T,
+  {
+    f(&mut self.state.requests.workunit_metadata_map.write())
+  }
+
+  ///
+  /// Adds the given workunit metadata entry while the given Future runs, and then removes it (even
+  /// if the Future panics or is dropped before completing).
+  ///
+  pub async fn with_workunit_metadata<T>(
+    &self,
+    key: UserMetadataPyValue,
+    value: PyObject,
+    f: impl Future<Output = T>,
+  ) -> T {
+    let _entry =
+      ScopedMetadataEntry::insert(&self.state.requests.workunit_metadata_map, key, value);
+    f.await
+  }
+
+  ///
+  /// Records the roots (and their LastObserved values) of a batch of roots which was executed on
+  /// this Session.
+  ///
+  pub fn roots_extend(&self, new_roots: Vec<(Root, Option<LastObserved>)>) {
+    if self.state.requests.record_request(new_roots) {
+      self.handle.log_first_root_requested();
+    }
+  }
+
+  ///
+  /// The number of batches of roots which have been executed (or polled) on this Session. Unlike
+  /// the number of unique roots, repeated requests for the same roots each increment this count.
+  ///
+  pub fn request_count(&self) -> u64 {
+    self.state.requests.request_count()
+  }
+
+  pub fn roots_zip_last_observed(&self, inputs: &[Root]) -> Vec<(Root, Option<LastObserved>)> {
+    self.state.requests.zip_last_observed(inputs)
+  }
+
+  ///
+  /// Returns the LastObserved value for a single root, if it has been polled in this Session.
+  ///
+  pub fn root_last_observed(&self, root: &Root) -> Option<LastObserved> {
+    self.state.requests.last_observed(root)
+  }
+
+  ///
+  /// Polls the given root using its previous LastObserved value in this Session (if any), waiting
+  /// at least `poll_delay` if the root has not changed, and then records the new LastObserved
+  /// value for the next poll.
+  ///
+  pub async fn poll_root(&self, root: Root, poll_delay: Option<Duration>) -> ObservedValueResult {
+    let context = Context::new(self.core().clone(), self.clone());
+    let graph = &self.core().graph;
+    let node: NodeKey = root.clone().into();
+    let (result, last_observed, first_request) = self
+      .state
+      .requests
+      .poll(root, |last_observed| {
+        graph.poll(node, last_observed, poll_delay, &context)
+      })
+      .await?;
+    if first_request {
+      self.handle.log_first_root_requested();
+    }
+    Ok((
+      result
+        .try_into()
+        .unwrap_or_else(|e| panic!("A Node implementation was ambiguous: {:?}", e)),
+      Some(last_observed),
+    ))
+  }
+
+  ///
+  /// Associates a metadata Value with the given root for the current `run_id` of this Session.
+  ///
+  /// NB: This is experimental: it allows for attaching information to roots without threading it
+  /// through the Graph.
+  ///
+  pub fn set_root_metadata(&self, root: Root, value: Value) {
+    self.state.requests.set_root_metadata(root, value);
+  }
+
+  pub fn root_metadata(&self, root: &Root) -> Option<Value> {
+    self.state.requests.root_metadata(root)
+  }
+
+  pub fn roots_nodes(&self) -> Vec<NodeKey> {
+    let roots = self.state.requests.roots.lock();
+    roots.keys().map(|r| r.clone().into()).collect()
+  }
+
+  ///
+  /// Short, sorted descriptions of the roots which have been requested on this Session (of the
+  /// form `{product} for {params}`), for use in a one-line status: this is much cheaper than
+  /// rendering the workunit tree.
+  ///
+  pub fn active_root_descriptions(&self) -> Vec<String> {
+    self
+      .state
+      .requests
+      .root_descriptions(|root| format!("{} for {}", root.product, root.params))
+  }
+
+  pub fn session_values(&self) -> PyObject {
+    self.state.session_values.get()
+  }
+
+  ///
+  /// Captures the current `session_values`, so that they may later be restored (e.g. before
+  /// retrying a failed run) via `restore_session_values`.
+  ///
+  pub fn snapshot_session_values(&self) -> PyObject {
+    self.session_values()
+  }
+
+  ///
+  /// Replaces the current `session_values` with a value captured by `snapshot_session_values`.
+  ///
+  pub fn restore_session_values(&self, snapshot: PyObject) {
+    let previous = self.state.session_values.restore(snapshot);
+    // NB: Drop the previous value only after the lock has been released, since dropping it might
+    // run Python code.
+    std::mem::drop(previous);
+  }
+
+  pub fn preceding_graph_size(&self) -> usize {
+    self.state.preceding_graph_size
+  }
+
+  ///
+  /// Returns metrics for this Session, and for the Scheduler that it is running on.
+  ///
+  pub fn metrics(&self) -> HashMap<&'static str, i64> {
+    let core = self.core();
+    let context = Context::new(core.clone(), self.clone());
+    let mut m = HashMap::new();
+    m.insert("affected_file_count", {
+      let mut count = 0;
+      core
+        .graph
+        .visit_live_reachable(&self.roots_nodes(), &context, |n, _| {
+          if n.fs_subject().is_some() {
+            count += 1;
+          }
+        });
+      count
+    });
+    m.insert("preceding_graph_size", self.preceding_graph_size() as i64);
+    for (name, value) in MetricsSnapshot::COUNTERS {
+      m.insert(*name, value(self));
+    }
+    m
+  }
+
+  ///
+  /// Captures the current `metrics`, for comparison with a later snapshot via
+  /// `MetricsSnapshot::delta`.
+  ///
+  pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+    MetricsSnapshot(self.metrics())
+  }
+
+  pub fn workunit_store(&self) -> WorkunitStore {
+    self.state.workunit_store.clone()
+  }
+
+  ///
+  /// Returns the currently running workunits for this Session as a forest, which (unlike the
+  /// straggler report) includes every level of the hierarchy.
+  ///
+  pub fn active_workunit_tree(&self) -> Vec<WorkunitTreeNode> {
+    self.state.workunit_store.running_tree()
+  }
+
+  ///
+  /// Enables or disables logging of workunits as they start for this Session (and any clones of
+  /// it). By default, this is enabled only when the dynamic UI is not in use.
+  ///
+  /// NB: This affects only logging: workunits which start while it is disabled are still recorded
+  /// (and so are still reported as stragglers, for example).
+  ///
+  pub fn set_log_starting_workunits(&self, enabled: bool) {
+    self
+      .state
+      .workunit_store
+      .set_log_starting_workunits(enabled);
+  }
+
+  ///
+  /// Returns a best-effort (non-blocking) answer to whether a ConsoleUI is rendering for this
+  /// Session, in which case output should be routed through the UI rather than written directly.
+  ///
+  pub fn has_console_ui(&self) -> bool {
+    self.handle.has_console_ui()
+  }
+
+  ///
+  /// Returns the current display mode of this Session, or `SessionDisplayMode::Unknown` if the
+  /// display is currently in use.
+  ///
+  pub fn display_mode(&self) -> SessionDisplayMode {
+    self.handle.display_mode()
+  }
+
+  pub fn build_id(&self) -> String {
+    self.handle.build_id()
   }
 
-  pub fn core(&self) -> &Arc<Core> {
-    &self.state.core
+  ///
+  /// Replaces the build_id of this Session (but not of other clones of it), for use when the final
+  /// build_id is only known after the Session has been created. May only be called once.
+  ///
+  pub fn set_build_id(&self, build_id: String) -> Result<(), String> {
+    self.handle.set_build_id(build_id)
+  }
+
+  pub fn run_id(&self) -> RunId {
+    self.state.requests.run_id()
   }
 
   ///
-  /// Cancels this Session.
+  /// The time since this Session was created.
   ///
-  pub fn cancel(&self) {
-    self.handle.cancel();
+  pub fn elapsed(&self) -> Duration {
+    self.state.start_time.elapsed()
   }
 
   ///
-  /// Returns true if this Session has been cancelled.
+  /// The time since the current `run_id` started (i.e. since the most recent `new_run_id`, or since
+  /// this Session was created): for a `--loop`, the duration of the current iteration.
   ///
-  pub fn is_cancelled(&self) -> bool {
-    self.handle.cancelled.poll_triggered()
+  /// NB: Shallow clones share this timing with the Session that they were cloned from.
+  ///
+  pub fn iteration_elapsed(&self) -> Duration {
+    self.state.requests.iteration_elapsed()
   }
 
   ///
-  /// Returns only if this Session has been cancelled.
+  /// Returns a human-readable (but stable and parseable) single line describing this Session: its
+  /// build_id, current run_id, whether it is isolated or cancelled, and its elapsed time.
   ///
-  pub async fn cancelled(&self) {
-    self.handle.cancelled.triggered().await;
+  pub fn describe(&self) -> String {
+    self.handle.describe(
+      self.run_id(),
+      self.elapsed(),
+      &self.state.requests.labels.read(),
+    )
   }
 
-  pub fn with_metadata_map<F, T>(&self, f: F) -> T
-  where
-    F: FnOnce(&mut HashMap<UserMetadataPyValue, PyObject>) -> T,
-  {
-    f(&mut self.state.workunit_metadata_map.write())
+  ///
+  /// Sets a label on this Session (overwriting any existing value for the key), for use in grouping
+  /// Sessions in metrics. Labels are shared with isolated clones of this Session.
+  ///
+  pub fn set_label(&self, key: String, value: String) {
+    self.state.requests.set_label(key, value);
   }
 
-  pub fn roots_extend(&self, new_roots: Vec<(Root, Option<LastObserved>)>) {
-    let mut roots = self.state.roots.lock();
-    roots.extend(new_roots);
+  pub fn labels(&self) -> HashMap<String, String> {
+    self.state.requests.labels()
   }
 
-  pub fn roots_zip_last_observed(&self, inputs: &[Root]) -> Vec<(Root, Option<LastObserved>)> {
-    let roots = self.state.roots.lock();
-    inputs
-      .iter()
-      .map(|root| {
-        let last_observed = roots.get(root).cloned().unwrap_or(None);
-        (root.clone(), last_observed)
-      })
-      .collect()
+  pub fn new_run_id(&self) {
+    let run_id = self.state.core.sessions.generate_run_id();
+    let previous_run_id = self.state.requests.start_run(run_id);
+    self.started_run_id(previous_run_id, run_id);
   }
 
-  pub fn roots_nodes(&self) -> Vec<NodeKey> {
-    let roots = self.state.roots.lock();
-    roots.keys().map(|r| r.clone().into()).collect()
+  ///
+  /// Starts a new `run_id` if `force_uncacheable` is set, so that no uncacheable values are reused
+  /// by the next request. Called before each request on this Session.
+  ///
+  pub fn new_run_id_if_uncacheable(&self) {
+    let sessions = &self.state.core.sessions;
+    if let Some((previous_run_id, run_id)) = self
+      .state
+      .requests
+      .force_new_run_id(|| sessions.generate_run_id())
+    {
+      self.started_run_id(previous_run_id, run_id);
+    }
   }
 
-  pub fn session_values(&self) -> PyObject {
-    self.state.session_values.lock().clone()
+  fn started_run_id(&self, previous_run_id: RunId, run_id: RunId) {
+    self
+      .state
+      .core
+      .sessions
+      .register_run_id(Some(previous_run_id), run_id, self);
+    self.handle.log_started_run_id(run_id);
+    self.state.run_id_callbacks.notify(run_id);
   }
 
-  pub fn preceding_graph_size(&self) -> usize {
-    self.state.preceding_graph_size
+  ///
+  /// Resets this Session to a fresh state so that it may be reused for an unrelated run: clears
+  /// its roots, workunit metadata, labels and counters, and then starts a new `run_id`. The
+  /// `build_id`, Core, WorkunitStore and session values are preserved, as is `elapsed` (but not
+  /// `iteration_elapsed`).
+  ///
+  /// NB: Must not be called while work is in flight on this Session (or any of its clones), since
+  /// that work would observe a mix of the old and new state.
+  ///
+  pub fn reset(&self) {
+    let metadata = self.state.requests.reset();
+    std::mem::drop(metadata);
+    self
+      .handle
+      .display_lock_wait_nanos
+      .store(0, atomic::Ordering::Relaxed);
+    self.new_run_id();
   }
 
-  pub fn workunit_store(&self) -> WorkunitStore {
-    self.state.workunit_store.clone()
+  ///
+  /// Registers a callback to be invoked with the new `run_id` each time `new_run_id` is called on
+  /// this Session (or any of its clones), after the new value has been stored.
+  ///
+  /// NB: Callbacks run synchronously on the thread which called `new_run_id`, so they must be cheap
+  /// and must not block.
+  ///
+  pub fn on_run_id_change(&self, f: impl Fn(RunId) + Send + Sync + 'static) {
+    self.state.run_id_callbacks.register(Arc::new(f));
   }
 
-  pub fn build_id(&self) -> &String {
-    &self.handle.build_id
+  ///
+  /// Generates a fresh `run_id` without replacing this Session's current `run_id` (unlike
+  /// `new_run_id`), for use in a scoped execution (such as speculation) whose uncacheable results
+  /// should not be visible to the rest of the run.
+  ///
+  pub fn fork_run_id(&self) -> RunId {
+    self
+      .state
+      .core
+      .sessions
+      .fork_run_id(Arc::downgrade(&self.handle), Arc::downgrade(&self.state))
   }
 
-  pub fn run_id(&self) -> RunId {
-    RunId(self.state.run_id.load(atomic::Ordering::SeqCst))
+  ///
+  /// When enabled, a fresh `run_id` is generated before each root request on this Session, so that
+  /// no previously computed uncacheable values are reused: useful for debugging caching bugs for a
+  /// single run without changing global configuration.
+  ///
+  /// NB: Because every request observes a new `run_id`, all uncacheable rules (and everything that
+  /// depends on them) will re-run on each request, which can be substantially slower.
+  ///
+  pub fn set_force_uncacheable(&self, on: bool) {
+    self
+      .state
+      .requests
+      .force_uncacheable
+      .store(on, atomic::Ordering::SeqCst);
   }
 
-  pub fn new_run_id(&self) {
-    self.state.run_id.store(
-      self.state.core.sessions.generate_run_id().0,
-      atomic::Ordering::SeqCst,
-    );
+  ///
+  /// Returns true if all rules should be treated as uncacheable for requests on this Session.
+  ///
+  pub fn force_uncacheable(&self) -> bool {
+    self
+      .state
+      .requests
+      .force_uncacheable
+      .load(atomic::Ordering::SeqCst)
   }
 
   pub async fn with_console_ui_disabled<T>(&self, f: impl Future<Output = T>) -> T {
-    match *self.handle.display.lock().await {
+    match *self.handle.lock_display().await {
       SessionDisplay::ConsoleUI(ref mut ui) => ui.with_console_ui_disabled(f).await,
       SessionDisplay::Logging { .. } => f.await,
     }
   }
 
+  ///
+  /// Sets the delay before the first check for straggling workunits when the dynamic UI is
+  /// disabled, which is otherwise equal to the interval between subsequent checks. Takes effect at
+  /// the next `maybe_display_initialize`.
+  ///
+  pub async fn set_initial_straggler_delay(&self, delay: Duration) {
+    if let SessionDisplay::Logging {
+      ref mut initial_straggler_delay,
+      ..
+    } = *self.handle.lock_display().await
+    {
+      *initial_straggler_delay = delay;
+    }
+  }
+
   pub async fn maybe_display_initialize(&self, executor: &Executor) {
-    let result = match *self.handle.display.lock().await {
-      SessionDisplay::ConsoleUI(ref mut ui) => ui.initialize(executor.clone()),
-      SessionDisplay::Logging {
-        ref mut straggler_deadline,
-        ..
-      } => {
-        *straggler_deadline = Some(Instant::now() + STRAGGLER_LOGGING_INTERVAL);
-        Ok(())
-      }
-    };
+    let result = self.handle.lock_display().await.initialize(executor);
     if let Err(e) = result {
       warn!("{}", e);
     }
   }
 
+  ///
+  /// The cumulative time that this Session has spent waiting to acquire its display lock (e.g. to
+  /// initialize or tear down the display), which may be used to diagnose stalls caused by the UI.
+  ///
+  pub fn display_lock_wait(&self) -> Duration {
+    self.handle.display_lock_wait()
+  }
+
   pub async fn maybe_display_teardown(&self) {
-    let teardown = match *self.handle.display.lock().await {
-      SessionDisplay::ConsoleUI(ref mut ui) => ui.teardown().boxed(),
-      SessionDisplay::Logging {
-        ref mut straggler_deadline,
-        ..
-      } => {
-        *straggler_deadline = None;
-        async { Ok(()) }.boxed()
-      }
-    };
-    if let Err(e) = teardown.await {
-      warn!("{}", e);
-    }
+    self.handle.display_teardown().await
+  }
+
+  ///
+  /// Waits until `maybe_display_teardown` has completed (successfully or not) for this Session, at
+  /// which point any ConsoleUI has released the terminal.
+  ///
+  /// NB: Once teardown has completed, this continues to return immediately even if the display is
+  /// later initialized again.
+  ///
+  pub async fn await_display_teardown(&self) {
+    self.handle.teardown_complete.triggered().await
   }
 
   pub fn maybe_display_render(&self) {
@@ -326,41 +1888,93 @@ impl Session {
       // Else, the UI is currently busy: skip rendering.
       return;
     };
-    match *display {
-      SessionDisplay::ConsoleUI(ref mut ui) => ui.render(),
-      SessionDisplay::Logging {
-        straggler_threshold,
-        ref mut straggler_deadline,
-      } => {
-        if straggler_deadline
-          .map(|sd| sd < Instant::now())
-          .unwrap_or(false)
-        {
-          *straggler_deadline = Some(Instant::now() + STRAGGLER_LOGGING_INTERVAL);
-          let straggling_workunits = self
-            .state
-            .workunit_store
-            .straggling_workunits(straggler_threshold);
-          if !straggling_workunits.is_empty() {
-            log::info!(
-              "Long running tasks:\n  {}",
-              straggling_workunits
-                .into_iter()
-                .map(|(duration, desc)| format!(
-                  "{}\t{}",
-                  format_workunit_duration_ms!(duration.as_millis()),
-                  desc
-                ))
-                .collect::<Vec<_>>()
-                .join("\n  ")
-            );
-          }
-        }
-      }
-    }
+    display.render(&self.state.workunit_store, false);
+  }
+
+  ///
+  /// Immediately renders the ConsoleUI, or logs any straggling workunits (regardless of whether
+  /// the next straggler check is due, which is then rescheduled).
+  ///
+  pub async fn force_straggler_report(&self) {
+    self
+      .handle
+      .lock_display()
+      .await
+      .render(&self.state.workunit_store, true);
+  }
+}
+
+///
+/// A snapshot of Session metrics: see `Session::metrics_snapshot`.
+///
+#[derive(Clone, Debug)]
+pub struct MetricsSnapshot(HashMap<&'static str, i64>);
+
+impl MetricsSnapshot {
+  // Metrics which accumulate over time, and for which a difference between snapshots is
+  // meaningful, along with how to compute them. All other metrics are point-in-time values.
+  const COUNTERS: &'static [(&'static str, fn(&Session) -> i64)] = &[
+    ("resulting_graph_size", |session| {
+      session.core().graph.len() as i64
+    }),
+    ("request_count", |session| session.request_count() as i64),
+    ("display_lock_wait_ms", |session| {
+      session.display_lock_wait().as_millis() as i64
+    }),
+  ];
+
+  ///
+  /// Computes the change from this snapshot to a later snapshot for counter-like metrics (e.g.
+  /// `resulting_graph_size`, the difference of which is the growth of the graph), and passes
+  /// through the later value of point-in-time metrics.
+  ///
+  pub fn delta(&self, later: &MetricsSnapshot) -> HashMap<String, i64> {
+    later
+      .0
+      .iter()
+      .map(|(&name, &later_value)| {
+        let value = if Self::COUNTERS.iter().any(|(counter, _)| *counter == name) {
+          later_value - self.0.get(name).cloned().unwrap_or(0)
+        } else {
+          later_value
+        };
+        (name.to_owned(), value)
+      })
+      .collect()
+  }
+}
+
+///
+/// A source of keyboard interrupts: abstracted to allow for delivering fake signals in tests.
+///
+#[async_trait]
+trait SignalStream: Send + 'static {
+  ///
+  /// Waits for the next signal, returning None if no more signals will be received.
+  ///
+  async fn recv(&mut self) -> Option<()>;
+}
+
+#[async_trait]
+impl SignalStream for Signal {
+  async fn recv(&mut self) -> Option<()> {
+    Signal::recv(self).await
   }
 }
 
+///
+/// A cheap summary of the state of a `Sessions` instance, for use in liveness probes.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionsHealth {
+  /// False once `Sessions::shutdown` has begun: no new Sessions may be created.
+  pub accepting_sessions: bool,
+  /// The number of Sessions which are currently live. See `Sessions::live_count`.
+  pub live_count: usize,
+  /// True if keyboard interrupts are being monitored. See `Sessions::signal_handling_active`.
+  pub signal_handling_active: bool,
+}
+
 ///
 /// A collection of all live Sessions.
 ///
@@ -374,54 +1988,147 @@ pub struct Sessions {
   /// If the wrapping Option is None, it is because `fn shutdown` is running, and the associated
   /// Core/Scheduler are being shut down.
   sessions: Arc<Mutex<Option<Vec<Weak<SessionHandle>>>>>,
+  /// While `fn shutdown` is running, the Sessions that it is waiting for: an interrupt during
+  /// shutdown cancels all of them.
+  shutting_down_sessions: Arc<Mutex<Vec<Weak<SessionHandle>>>>,
   /// Handle to kill the signal monitoring task when this object is killed.
   signal_task_abort_handle: AbortHandle,
+  /// True while the signal monitoring task is (or is about to be) running. If the task could not
+  /// be spawned or has exited, keyboard interrupts will not cancel Sessions.
+  signal_handling_active: Arc<AtomicBool>,
   /// A generator for RunId values. Although this is monotonic, there is no meaning assigned to
   /// ordering: only equality is relevant.
   run_id_generator: AtomicU32,
+  /// If configured, bounds the number of isolated clones which may be live at once, in order to
+  /// prevent background work from starving foreground work.
+  background_clone_permits: Option<Arc<Semaphore>>,
+  /// The handles (of a Session and any shallow clones of it, in the order they were registered)
+  /// and shared state using each current RunId. Entries move when a Session observes a new RunId,
+  /// and entries for dead Sessions are removed by `prune` (including as new Sessions are created).
+  sessions_by_run_id: Mutex<HashMap<RunId, (Vec<Weak<SessionHandle>>, Weak<SessionState>)>>,
+  /// Callbacks to invoke each time a Session ends.
+  session_end_callbacks: SessionEndCallbacks,
 }
 
 impl Sessions {
   pub fn new(executor: &Executor) -> Result<Sessions, String> {
+    Self::with_interrupt_signal(executor, None)
+  }
+
+  ///
+  /// Creates a Sessions instance which will allow at most `max_background_clones` isolated clones
+  /// to be live at once. See `Session::isolated_shallow_clone`.
+  ///
+  pub fn with_max_background_clones(
+    executor: &Executor,
+    max_background_clones: usize,
+  ) -> Result<Sessions, String> {
+    Self::with_interrupt_signal(executor, Some(max_background_clones))
+  }
+
+  fn with_interrupt_signal(
+    executor: &Executor,
+    max_background_clones: Option<usize>,
+  ) -> Result<Sessions, String> {
+    let signal_stream = signal(SignalKind::interrupt())
+      .map_err(|err| format!("Failed to install interrupt handler: {}", err))?;
+    Ok(Self::with_signal_stream(
+      executor,
+      signal_stream,
+      max_background_clones,
+    ))
+  }
+
+  fn with_signal_stream(
+    executor: &Executor,
+    signal_stream: impl SignalStream,
+    max_background_clones: Option<usize>,
+  ) -> Sessions {
     let sessions: Arc<Mutex<Option<Vec<Weak<SessionHandle>>>>> =
       Arc::new(Mutex::new(Some(Vec::new())));
-    // A task that watches for keyboard interrupts arriving at this process, and cancels all
-    // non-isolated Sessions.
-    let signal_task_abort_handle = {
-      let mut signal_stream = signal(SignalKind::interrupt())
-        .map_err(|err| format!("Failed to install interrupt handler: {}", err))?;
-      let (abort_handle, abort_registration) = AbortHandle::new_pair();
-      let sessions = sessions.clone();
-      let _ = executor.spawn(Abortable::new(
-        async move {
-          loop {
-            let _ = signal_stream.recv().await;
-            let cancellable_sessions = {
-              let sessions = sessions.lock();
-              if let Some(ref sessions) = *sessions {
-                sessions
-                  .iter()
-                  .flat_map(|session| session.upgrade())
-                  .filter(|session| !session.isolated)
-                  .collect::<Vec<_>>()
-              } else {
-                vec![]
-              }
-            };
-            for session in cancellable_sessions {
-              session.cancel();
-            }
-          }
-        },
-        abort_registration,
-      ));
-      abort_handle
-    };
-    Ok(Sessions {
+    let shutting_down_sessions = Arc::new(Mutex::new(Vec::new()));
+    let (signal_task_abort_handle, signal_handling_active) = Self::spawn_signal_task(
+      executor,
+      sessions.clone(),
+      shutting_down_sessions.clone(),
+      signal_stream,
+    );
+    Sessions {
       sessions,
+      shutting_down_sessions,
       signal_task_abort_handle,
+      signal_handling_active,
       run_id_generator: AtomicU32::new(0),
-    })
+      background_clone_permits: max_background_clones.map(|max| Arc::new(Semaphore::new(max))),
+      sessions_by_run_id: Mutex::new(HashMap::new()),
+      session_end_callbacks: SessionEndCallbacks::default(),
+    }
+  }
+
+  ///
+  /// Spawns a task that watches for keyboard interrupts arriving on the given stream, and cancels
+  /// all non-isolated Sessions. If `shutdown` is in progress, all remaining Sessions (including
+  /// isolated Sessions) are cancelled instead, which allows the shutdown to complete.
+  ///
+  /// Returns an AbortHandle for the task, and a flag which will be cleared if the task fails to
+  /// spawn or exits.
+  ///
+  fn spawn_signal_task(
+    executor: &Executor,
+    sessions: Arc<Mutex<Option<Vec<Weak<SessionHandle>>>>>,
+    shutting_down_sessions: Arc<Mutex<Vec<Weak<SessionHandle>>>>,
+    mut signal_stream: impl SignalStream,
+  ) -> (AbortHandle, Arc<AtomicBool>) {
+    let (abort_handle, abort_registration) = AbortHandle::new_pair();
+    let signal_handling_active = Arc::new(AtomicBool::new(true));
+    // NB: The guard is created outside of the task so that it is dropped (and reports the failure)
+    // even if the task is dropped without ever being polled.
+    let guard = SignalTaskGuard(signal_handling_active.clone());
+    let _ = executor.spawn(Abortable::new(
+      async move {
+        let _guard = guard;
+        while signal_stream.recv().await.is_some() {
+          let cancellable_sessions = {
+            let sessions = sessions.lock();
+            if let Some(ref sessions) = *sessions {
+              sessions
+                .iter()
+                .flat_map(|session| session.upgrade())
+                .filter(|session| !session.isolated)
+                .collect::<Vec<_>>()
+            } else {
+              log::warn!(
+                "Shutdown is already in progress: cancelling all remaining Sessions, including \
+                 isolated Sessions."
+              );
+              shutting_down_sessions
+                .lock()
+                .iter()
+                .flat_map(|session| session.upgrade())
+                .collect::<Vec<_>>()
+            }
+          };
+          let cancelled_count = cancellable_sessions.len();
+          for session in cancellable_sessions {
+            log::info!(
+              "Cancelling Session `{}` due to interrupt.",
+              session.build_id()
+            );
+            session.cancel();
+          }
+          log::info!("Interrupt cancelled {} Session(s).", cancelled_count);
+        }
+      },
+      abort_registration,
+    ));
+    (abort_handle, signal_handling_active)
+  }
+
+  ///
+  /// Returns true if keyboard interrupts are being monitored (and will cancel Sessions).
+  ///
+  pub fn signal_handling_active(&self) -> bool {
+    self.signal_handling_active.load(atomic::Ordering::SeqCst)
   }
 
   fn add(&self, handle: &Arc<SessionHandle>) -> Result<(), String> {
@@ -429,52 +2136,292 @@ impl Sessions {
     if let Some(ref mut sessions) = *sessions {
       sessions.retain(|weak_handle| weak_handle.upgrade().is_some());
       sessions.push(Arc::downgrade(handle));
+      self.prune_run_ids();
       Ok(())
     } else {
       Err("The scheduler is shutting down: no new sessions may be created.".to_string())
     }
   }
 
+  ///
+  /// Eagerly removes completed Sessions from this collection, and releases the memory they used.
+  ///
+  /// Completed Sessions are otherwise only removed when new Sessions are created, so a host which
+  /// creates Sessions infrequently may call this periodically to bound memory usage.
+  ///
+  pub fn prune(&self) {
+    if let Some(ref mut sessions) = *self.sessions.lock() {
+      sessions.retain(|weak_handle| weak_handle.upgrade().is_some());
+      sessions.shrink_to_fit();
+    }
+    self.prune_run_ids();
+  }
+
+  fn prune_run_ids(&self) {
+    let mut sessions_by_run_id = self.sessions_by_run_id.lock();
+    for (handles, _) in sessions_by_run_id.values_mut() {
+      handles.retain(|handle| handle.strong_count() > 0);
+    }
+    sessions_by_run_id
+      .retain(|_, (handles, state)| !handles.is_empty() && state.strong_count() > 0);
+  }
+
+  ///
+  /// Registers a callback to be invoked with a summary of each Session (or shallow clone) which
+  /// ends after this call, whether by completing, or by being cancelled or dropped: e.g. to record
+  /// Session durations in a histogram.
+  ///
+  /// NB: Callbacks run on the Executor, and so must be cheap and must not block.
+  ///
+  pub fn on_session_end(&self, f: impl Fn(SessionSummary) + Send + Sync + 'static) {
+    self.session_end_callbacks.register(Arc::new(f));
+  }
+
+  ///
+  /// The number of Sessions which are currently live.
+  ///
+  pub fn live_count(&self) -> usize {
+    if let Some(ref sessions) = *self.sessions.lock() {
+      Self::count_live(sessions)
+    } else {
+      0
+    }
+  }
+
+  fn count_live(sessions: &[Weak<SessionHandle>]) -> usize {
+    sessions
+      .iter()
+      .filter(|weak_handle| weak_handle.strong_count() > 0)
+      .count()
+  }
+
+  ///
+  /// Acquires a permit for a new background (isolated) Session, if background Sessions are
+  /// bounded. Fails without waiting if no permits are available.
+  ///
+  fn acquire_background_permit(&self) -> Result<Option<OwnedSemaphorePermit>, String> {
+    if let Some(ref permits) = self.background_clone_permits {
+      permits.clone().try_acquire_owned().map(Some).map_err(|_| {
+        "The maximum number of concurrent background Sessions are already running.".to_owned()
+      })
+    } else {
+      Ok(None)
+    }
+  }
+
+  ///
+  /// Reports whether this instance is accepting new Sessions, how many are live, and whether
+  /// keyboard interrupts are being monitored, without blocking on any Session.
+  ///
+  pub fn health(&self) -> SessionsHealth {
+    let (accepting_sessions, live_count) = match *self.sessions.lock() {
+      Some(ref sessions) => (true, Self::count_live(sessions)),
+      None => (false, 0),
+    };
+    SessionsHealth {
+      accepting_sessions,
+      live_count,
+      signal_handling_active: self.signal_handling_active(),
+    }
+  }
+
   fn generate_run_id(&self) -> RunId {
     RunId(self.run_id_generator.fetch_add(1, atomic::Ordering::SeqCst))
   }
 
+  ///
+  /// Generates a fresh RunId, and registers it for the given Session alongside its current RunId.
+  /// See `Session::fork_run_id`.
+  ///
+  fn fork_run_id(&self, handle: Weak<SessionHandle>, state: Weak<SessionState>) -> RunId {
+    let run_id = self.generate_run_id();
+    self.register_run_id_weak(None, run_id, handle, state);
+    run_id
+  }
+
+  ///
+  /// Records that the given Session is now using `run_id` rather than `previous_run_id`.
+  ///
+  fn register_run_id(&self, previous_run_id: Option<RunId>, run_id: RunId, session: &Session) {
+    self.register_run_id_weak(
+      previous_run_id,
+      run_id,
+      Arc::downgrade(&session.handle),
+      Arc::downgrade(&session.state),
+    );
+  }
+
+  ///
+  /// NB: The handles which were using `previous_run_id` (i.e., the other shallow clones of the
+  /// Session) move to `run_id` along with the given handle, since they share its state.
+  ///
+  fn register_run_id_weak(
+    &self,
+    previous_run_id: Option<RunId>,
+    run_id: RunId,
+    handle: Weak<SessionHandle>,
+    state: Weak<SessionState>,
+  ) {
+    let mut sessions_by_run_id = self.sessions_by_run_id.lock();
+    let previous_handles = previous_run_id
+      .and_then(|previous_run_id| sessions_by_run_id.remove(&previous_run_id))
+      .map(|(handles, _)| handles)
+      .unwrap_or_default();
+    let (handles, _) = sessions_by_run_id
+      .entry(run_id)
+      .or_insert_with(|| (Vec::new(), state));
+    for handle in previous_handles.into_iter().chain(std::iter::once(handle)) {
+      if !handles.iter().any(|existing| existing.ptr_eq(&handle)) {
+        handles.push(handle);
+      }
+    }
+  }
+
+  ///
+  /// Returns a live Session which is currently using the given RunId (including via
+  /// `Session::fork_run_id`), if any. If a Session and its shallow clones share the RunId, the
+  /// earliest registered handle which is still live (generally the original Session) is used.
+  ///
+  pub fn session_for_run_id(&self, run_id: RunId) -> Option<Session> {
+    let (handle, state) = self.handle_for_run_id(run_id)?;
+    Some(Session {
+      handle,
+      state: state.upgrade()?,
+    })
+  }
+
+  ///
+  /// Returns the earliest registered live handle which is using the given RunId, along with the
+  /// state that it shares with its shallow clones. See `session_for_run_id`.
+  ///
+  fn handle_for_run_id(&self, run_id: RunId) -> Option<(Arc<SessionHandle>, Weak<SessionState>)> {
+    let sessions_by_run_id = self.sessions_by_run_id.lock();
+    let (handles, state) = sessions_by_run_id.get(&run_id)?;
+    Some((handles.iter().find_map(Weak::upgrade)?, state.clone()))
+  }
+
+  ///
+  /// Shuts down in two phases: first, all live Sessions stop accepting new requests (see
+  /// `Session::begin_drain`), and up to `drain_timeout` is allowed for their in-flight requests to
+  /// complete. Then, any Sessions which are still running work are cancelled, and the normal
+  /// `shutdown` proceeds with `shutdown_timeout`.
+  ///
+  pub async fn drain_and_shutdown(
+    &self,
+    drain_timeout: Duration,
+    shutdown_timeout: Duration,
+  ) -> Result<(), String> {
+    let handles = if let Some(ref sessions) = *self.sessions.lock() {
+      sessions
+        .iter()
+        .filter_map(|weak_handle| weak_handle.upgrade())
+        .collect::<Vec<_>>()
+    } else {
+      vec![]
+    };
+    for handle in &handles {
+      handle.begin_drain();
+    }
+
+    let drained = tokio::time::timeout(
+      drain_timeout,
+      future::join_all(handles.iter().map(|handle| handle.wait_for_idle())),
+    )
+    .await;
+    if drained.is_err() {
+      for handle in &handles {
+        if handle.in_flight.load(atomic::Ordering::SeqCst) > 0 {
+          log::info!(
+            "Cancelling Session `{}`: it did not drain within {:?}.",
+            handle.build_id(),
+            drain_timeout
+          );
+          handle.cancel();
+        }
+      }
+    }
+    // NB: Release our references to the handles, so that `shutdown` can observe them being dropped.
+    std::mem::drop(handles);
+
+    self.shutdown(shutdown_timeout).await
+  }
+
   ///
   /// Shuts down this Sessions instance by waiting for all existing Sessions to exit.
   ///
   /// Waits at most `timeout` for Sessions to complete.
   ///
   pub async fn shutdown(&self, timeout: Duration) -> Result<(), String> {
-    if let Some(sessions) = self.sessions.lock().take() {
+    let sessions = {
+      let mut sessions_guard = self.sessions.lock();
+      let sessions = sessions_guard.take();
+      // NB: Record the Sessions being shut down while the lock is held, so that an interrupt
+      // which observes that shutdown has begun can always find them.
+      if let Some(ref sessions) = sessions {
+        *self.shutting_down_sessions.lock() = sessions.clone();
+      }
+      sessions
+    };
+    let result = if let Some(sessions) = sessions {
       // Collect clones of the cancellation tokens for each Session, which allows us to watch for
       // them to have been dropped.
       let (build_ids, cancellation_latches): (Vec<_>, Vec<_>) = sessions
         .into_iter()
         .filter_map(|weak_handle| weak_handle.upgrade())
         .map(|handle| {
-          let build_id = handle.build_id.clone();
+          let build_id = handle.build_id();
           let cancelled = handle.cancelled.clone();
           let cancellation_triggered = async move {
             cancelled.triggered().await;
             log::info!("Shutdown completed: {:?}", build_id)
           };
-          (handle.build_id.clone(), cancellation_triggered)
+          (handle.build_id(), cancellation_triggered)
         })
         .unzip();
 
-      if !build_ids.is_empty() {
+      if build_ids.is_empty() {
+        Ok(())
+      } else {
         log::info!("Waiting for shutdown of: {:?}", build_ids);
         tokio::time::timeout(timeout, future::join_all(cancellation_latches))
           .await
-          .map_err(|_| format!("Some Sessions did not shutdown within {:?}.", timeout))?;
+          .map(|_| ())
+          .map_err(|_| format!("Some Sessions did not shutdown within {:?}.", timeout))
       }
-    }
-    Ok(())
+    } else {
+      Ok(())
+    };
+    // Once shutdown has completed (or timed out), there is nothing left for an interrupt to cancel.
+    self.shutting_down_sessions.lock().clear();
+    result
   }
 }
 
 impl Drop for Sessions {
   fn drop(&mut self) {
+    // Clear the flag first, so that the (expected) exit of the task is not reported as an error.
+    self
+      .signal_handling_active
+      .store(false, atomic::Ordering::SeqCst);
     self.signal_task_abort_handle.abort();
   }
 }
+
+///
+/// Clears the `signal_handling_active` flag of a `Sessions` instance when the signal monitoring
+/// task is dropped, and logs an error if the flag had not already been cleared by `Sessions`.
+///
+struct SignalTaskGuard(Arc<AtomicBool>);
+
+impl Drop for SignalTaskGuard {
+  fn drop(&mut self) {
+    if self.0.swap(false, atomic::Ordering::SeqCst) {
+      log::error!(
+        "The keyboard interrupt handler is not running: Ctrl+C will not cancel running work."
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests;
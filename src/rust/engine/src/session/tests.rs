@@ -0,0 +1,1356 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use async_latch::AsyncLatch;
+use async_trait::async_trait;
+use futures::FutureExt;
+use parking_lot::{Mutex, RwLock};
+use task_executor::Executor;
+use testutil_logger::captured_logs;
+use tokio::sync::mpsc;
+use ui::ConsoleUI;
+use workunit_store::{
+  RunId, RunningWorkunit, UserMetadataPyValue, WorkunitMetadata, WorkunitStore,
+};
+
+use crate::session::{
+  BoundError, CancelGuard, MetricsSnapshot, RequestState, RunIdCallbacks, ScopedMetadataEntry,
+  SessionDisplay, SessionDisplayMode, SessionEndCallbacks, SessionHandle, SessionSummary,
+  SessionValues, Sessions, SessionsHealth, SignalStream, StragglerLogFormat,
+};
+
+#[async_trait]
+impl SignalStream for mpsc::UnboundedReceiver<()> {
+  async fn recv(&mut self) -> Option<()> {
+    mpsc::UnboundedReceiver::recv(self).await
+  }
+}
+
+fn handle(build_id: &str, isolated: bool) -> SessionHandle {
+  handle_with_display(
+    build_id,
+    isolated,
+    logging_display(Duration::from_secs(60), Duration::from_secs(30)),
+  )
+}
+
+fn logging_display(
+  straggler_threshold: Duration,
+  initial_straggler_delay: Duration,
+) -> SessionDisplay {
+  SessionDisplay::Logging {
+    straggler_threshold,
+    straggler_deadline: None,
+    initial_straggler_delay,
+    straggler_interval: Duration::from_secs(30),
+    previous_stragglers: HashSet::new(),
+    straggler_format: StragglerLogFormat::Human,
+    straggler_max_description_length: 200,
+  }
+}
+
+fn straggler_deadline(display: &mut SessionDisplay) -> &mut Option<Instant> {
+  match display {
+    SessionDisplay::Logging {
+      straggler_deadline, ..
+    } => straggler_deadline,
+    SessionDisplay::ConsoleUI(_) => unreachable!(),
+  }
+}
+
+fn straggler_interval(display: &SessionDisplay) -> Duration {
+  match display {
+    SessionDisplay::Logging {
+      straggler_interval, ..
+    } => *straggler_interval,
+    SessionDisplay::ConsoleUI(_) => unreachable!(),
+  }
+}
+
+///
+/// Starts a (never completed) workunit with the given description, which will be reported as a
+/// straggler for any threshold.
+///
+fn start_straggler(workunit_store: &WorkunitStore, desc: &str) -> RunningWorkunit {
+  workunit_store.init_thread_state(None);
+  let (_store_handle, workunit) = workunit_store::_start_workunit(
+    workunit_store.clone(),
+    desc.to_owned(),
+    WorkunitMetadata {
+      desc: Some(desc.to_owned()),
+      ..WorkunitMetadata::default()
+    },
+  );
+  workunit
+}
+
+fn handle_with_display(build_id: &str, isolated: bool, display: SessionDisplay) -> SessionHandle {
+  SessionHandle::new(
+    build_id.to_owned(),
+    AsyncLatch::new(),
+    isolated,
+    display,
+    None,
+  )
+}
+
+#[test]
+fn describe() {
+  let handle = handle("pants_run_1", false);
+  assert_eq!(
+    handle.describe(RunId(3), Duration::from_millis(1500), &HashMap::new()),
+    "build_id=pants_run_1 run_id=3 isolated=false cancelled=false elapsed_ms=1500 labels="
+  );
+
+  handle.cancel();
+  let labels = vec![
+    ("user".to_owned(), "alice".to_owned()),
+    ("ci".to_owned(), "true".to_owned()),
+  ]
+  .into_iter()
+  .collect();
+  assert_eq!(
+    handle.describe(RunId(4), Duration::from_millis(2000), &labels),
+    "build_id=pants_run_1 run_id=4 isolated=false cancelled=true elapsed_ms=2000 labels=ci=true,user=alice"
+  );
+}
+
+#[test]
+fn labels() {
+  let state = request_state();
+  state.set_label("user".to_owned(), "alice".to_owned());
+  state.set_label("ci".to_owned(), "true".to_owned());
+
+  // Setting a label again overwrites its value.
+  state.set_label("user".to_owned(), "bob".to_owned());
+  let labels = state.labels();
+  assert_eq!(
+    labels,
+    vec![
+      ("user".to_owned(), "bob".to_owned()),
+      ("ci".to_owned(), "true".to_owned()),
+    ]
+    .into_iter()
+    .collect()
+  );
+  assert_eq!(
+    handle("pants_run_1", false).describe(RunId(1), Duration::ZERO, &labels),
+    "build_id=pants_run_1 run_id=1 isolated=false cancelled=false elapsed_ms=0 labels=ci=true,user=bob"
+  );
+}
+
+#[tokio::test]
+async fn signal_cancels_non_isolated_sessions() {
+  let (signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, None);
+  let foreground = Arc::new(handle("foreground", false));
+  let background = Arc::new(handle("background", true));
+  sessions.add(&foreground).unwrap();
+  sessions.add(&background).unwrap();
+
+  signal_sender.send(()).unwrap();
+  tokio::time::timeout(Duration::from_secs(5), foreground.cancelled.triggered())
+    .await
+    .expect("The non-isolated Session should have been cancelled.");
+  assert!(!background.cancelled.poll_triggered());
+}
+
+#[tokio::test]
+async fn signal_cancellation_logs_build_ids() {
+  let logger = captured_logs();
+  let (signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, None);
+  let foreground = Arc::new(handle("logged_foreground", false));
+  let background = Arc::new(handle("logged_background", true));
+  sessions.add(&foreground).unwrap();
+  sessions.add(&background).unwrap();
+
+  // As with `Session::shallow_clone`: a non-isolated clone has its own cancellation latch, so
+  // cancelling it directly does not cancel the Session that it was cloned from.
+  let clone = Arc::new(handle("logged_clone", false));
+  sessions.add(&clone).unwrap();
+  clone.cancel();
+  assert!(!foreground.cancelled.poll_triggered());
+  assert!(logger.contains("Session `logged_clone` cancelled."));
+  assert!(!logger.any(|line| line.contains("logged_foreground")));
+
+  signal_sender.send(()).unwrap();
+  tokio::time::timeout(Duration::from_secs(5), foreground.cancelled.triggered())
+    .await
+    .expect("The non-isolated Session should have been cancelled.");
+  // The summary is logged after every Session has been cancelled.
+  tokio::time::timeout(Duration::from_secs(5), async {
+    while !logger.any(|line| line.starts_with("Interrupt cancelled")) {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  })
+  .await
+  .expect("The cancellation summary should have been logged.");
+
+  assert!(logger.contains("Cancelling Session `logged_foreground` due to interrupt."));
+  assert!(!logger.any(|line| line.contains("logged_background")));
+}
+
+fn two_stragglers() -> Vec<(Duration, String)> {
+  vec![
+    (Duration::from_millis(61000), "Scheduling: cat".to_owned()),
+    (Duration::from_millis(120500), "Running: \"dog\"".to_owned()),
+  ]
+}
+
+#[test]
+fn straggler_human_format() {
+  assert_eq!(
+    StragglerLogFormat::Human.format(two_stragglers(), 200),
+    "Long running tasks:\n  61.00s\tScheduling: cat\n  120.50s\tRunning: \"dog\""
+  );
+}
+
+#[test]
+fn straggler_json_format() {
+  let formatted = StragglerLogFormat::Json.format(two_stragglers(), 200);
+  assert!(!formatted.contains('\n'));
+  let parsed: serde_json::Value = serde_json::from_str(&formatted).unwrap();
+  assert_eq!(
+    parsed,
+    serde_json::json!({
+      "event": "stragglers",
+      "tasks": [
+        {"duration_ms": 61000, "desc": "Scheduling: cat"},
+        {"duration_ms": 120500, "desc": "Running: \"dog\""},
+      ],
+    })
+  );
+}
+
+#[tokio::test]
+async fn cancellation_token_clones() {
+  let handle = handle("pants_run_1", false);
+  let token = handle.cancellation_token();
+  let tokens = vec![token.clone(), token.clone(), token];
+  assert!(tokens.iter().all(|token| !token.is_cancelled()));
+
+  handle.cancel();
+  tokio::time::timeout(
+    Duration::from_secs(5),
+    futures::future::join_all(tokens.iter().map(|token| token.cancelled())),
+  )
+  .await
+  .expect("All tokens should have observed the cancellation.");
+  assert!(tokens.iter().all(|token| token.is_cancelled()));
+}
+
+#[tokio::test]
+async fn background_clone_permits_are_bounded() {
+  let (_signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, Some(2));
+
+  let first = sessions.acquire_background_permit().unwrap();
+  let second = sessions.acquire_background_permit().unwrap();
+  assert!(first.is_some() && second.is_some());
+  assert!(sessions.acquire_background_permit().is_err());
+
+  // Dropping a permit (as happens when an isolated clone is dropped) allows another to be created.
+  std::mem::drop(first);
+  assert!(sessions.acquire_background_permit().unwrap().is_some());
+}
+
+#[tokio::test]
+async fn background_clones_unbounded_by_default() {
+  let (_signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, None);
+  assert!(sessions.acquire_background_permit().unwrap().is_none());
+}
+
+#[tokio::test]
+async fn signal_handling_active() {
+  let (_signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, None);
+  assert!(sessions.signal_handling_active());
+}
+
+#[tokio::test]
+async fn health() {
+  let (_signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, None);
+  let live = Arc::new(handle("live", false));
+  sessions.add(&live).unwrap();
+  sessions.add(&Arc::new(handle("dead", false))).unwrap();
+  assert_eq!(
+    sessions.health(),
+    SessionsHealth {
+      accepting_sessions: true,
+      live_count: 1,
+      signal_handling_active: true,
+    }
+  );
+
+  std::mem::drop(live);
+  sessions.shutdown(Duration::from_secs(5)).await.unwrap();
+  assert_eq!(
+    sessions.health(),
+    SessionsHealth {
+      accepting_sessions: false,
+      live_count: 0,
+      signal_handling_active: true,
+    }
+  );
+}
+
+#[tokio::test]
+async fn signal_handling_inactive_when_stream_ends() {
+  let (signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, None);
+  std::mem::drop(signal_sender);
+  tokio::time::timeout(Duration::from_secs(5), async {
+    while sessions.signal_handling_active() {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  })
+  .await
+  .expect("Signal handling should have stopped once the stream ended.");
+}
+
+#[test]
+fn signal_handling_inactive_when_spawn_fails() {
+  // Create an Executor for a Runtime which is then shut down, so that spawning onto it fails.
+  let runtime = tokio::runtime::Runtime::new().unwrap();
+  let executor = {
+    let _context = runtime.enter();
+    Executor::new()
+  };
+  std::mem::drop(runtime);
+
+  let (_signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&executor, signal_receiver, None);
+  assert!(!sessions.signal_handling_active());
+}
+
+#[tokio::test]
+async fn cancel_and_wait_for_cooperating_work() {
+  let handle = Arc::new(handle("pants_run_1", false));
+  let work = handle.track_work();
+  let cancelled = handle.cancelled.clone();
+  let _ = Executor::new().spawn(async move {
+    cancelled.triggered().await;
+    std::mem::drop(work);
+  });
+
+  handle
+    .cancel_and_wait(Duration::from_secs(5))
+    .await
+    .unwrap();
+  assert!(handle.cancelled.poll_triggered());
+}
+
+#[tokio::test]
+async fn cancel_and_wait_timeout() {
+  let handle = Arc::new(handle("pants_run_1", false));
+  let _work = handle.track_work();
+
+  let err = handle
+    .cancel_and_wait(Duration::from_millis(100))
+    .await
+    .expect_err("Work which never exits should cause a timeout.");
+  assert!(err.contains("pants_run_1"), "Bad error message: {}", err);
+}
+
+#[test]
+fn straggler_description_truncated() {
+  let desc = "a".repeat(1000);
+  let formatted =
+    StragglerLogFormat::Human.format(vec![(Duration::from_millis(61000), desc.clone())], 200);
+  assert_eq!(
+    formatted,
+    format!("Long running tasks:\n  61.00s\t{}...", "a".repeat(200))
+  );
+
+  // Descriptions within the limit are unchanged.
+  let formatted =
+    StragglerLogFormat::Human.format(vec![(Duration::from_millis(61000), desc)], 1000);
+  assert_eq!(
+    formatted,
+    format!("Long running tasks:\n  61.00s\t{}", "a".repeat(1000))
+  );
+}
+
+#[tokio::test]
+async fn prune() {
+  let (_signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, None);
+
+  let handles = (0..1000)
+    .map(|i| Arc::new(handle(&format!("pants_run_{}", i), false)))
+    .collect::<Vec<_>>();
+  for handle in &handles {
+    sessions.add(handle).unwrap();
+  }
+  assert_eq!(sessions.live_count(), 1000);
+
+  std::mem::drop(handles);
+  assert_eq!(sessions.live_count(), 0);
+  assert!(sessions.sessions.lock().as_ref().unwrap().capacity() >= 1000);
+
+  sessions.prune();
+  assert_eq!(sessions.live_count(), 0);
+  assert_eq!(sessions.sessions.lock().as_ref().unwrap().capacity(), 0);
+}
+
+#[tokio::test]
+async fn has_console_ui() {
+  let logging = handle("logging", false);
+  assert!(!logging.has_console_ui());
+
+  let console_ui = handle_with_display(
+    "console_ui",
+    false,
+    SessionDisplay::ConsoleUI(ConsoleUI::new(WorkunitStore::new(false), 1)),
+  );
+  assert!(console_ui.has_console_ui());
+
+  // While the display is locked, a ConsoleUI is assumed.
+  let _display = logging.display.lock().await;
+  assert!(logging.has_console_ui());
+}
+
+#[test]
+fn initial_straggler_delay() {
+  let mut display = logging_display(Duration::from_secs(60), Duration::from_secs(120));
+  let before = Instant::now();
+  display.initialize(&Executor::new()).unwrap();
+  let initial_deadline = straggler_deadline(&mut display).unwrap();
+  assert!(initial_deadline >= before + Duration::from_secs(120));
+
+  // Not yet due, so not re-armed.
+  display.render(&WorkunitStore::new(false), false);
+  assert_eq!(*straggler_deadline(&mut display), Some(initial_deadline));
+
+  // Due: re-armed using the interval rather than the initial delay.
+  *straggler_deadline(&mut display) = Some(before);
+  display.render(&WorkunitStore::new(false), false);
+  let deadline = straggler_deadline(&mut display).unwrap();
+  assert!(deadline >= before + Duration::from_secs(30));
+  assert!(deadline < initial_deadline);
+}
+
+#[test]
+fn straggler_interval_backoff() {
+  let workunit_store = WorkunitStore::new(false);
+  let _first = start_straggler(&workunit_store, "first");
+  let mut display = logging_display(Duration::from_secs(0), Duration::from_secs(30));
+  let render = |display: &mut SessionDisplay| {
+    *straggler_deadline(display) = Some(Instant::now() - Duration::from_secs(1));
+    display.render(&workunit_store, false);
+    straggler_interval(display).as_secs()
+  };
+
+  // The first report is a change from nothing, and then a stable straggler backs off to the cap.
+  let intervals = (0..6).map(|_| render(&mut display)).collect::<Vec<_>>();
+  assert_eq!(intervals, vec![30, 60, 120, 240, 300, 300]);
+
+  // A material change resets the interval.
+  let _second = start_straggler(&workunit_store, "second");
+  assert_eq!(render(&mut display), 30);
+  assert_eq!(render(&mut display), 60);
+}
+
+#[test]
+fn lifecycle_logging() {
+  let logger = captured_logs();
+  // The lifecycle of a Session: creation, requests (of which only the first is logged), a new
+  // run_id, cancellation and drop.
+  let handle = handle("lifecycle", false);
+  let state = request_state();
+  handle.log_created(state.run_id());
+  for _ in 0..2 {
+    if state.record_request(vec![("a", None)]) {
+      handle.log_first_root_requested();
+    }
+  }
+  state.start_run(RunId(2));
+  handle.log_started_run_id(state.run_id());
+  handle.cancel();
+  std::mem::drop(handle);
+
+  let logs = logger
+    .lines()
+    .into_iter()
+    .filter(|line| line.contains("`lifecycle`"))
+    .collect::<Vec<_>>();
+  assert_eq!(
+    logs,
+    vec![
+      "Session `lifecycle` created with run_id 1.".to_owned(),
+      "Session `lifecycle` requested its first root.".to_owned(),
+      "Session `lifecycle` started run_id 2.".to_owned(),
+      "Session `lifecycle` cancelled.".to_owned(),
+      "Session `lifecycle` dropped.".to_owned(),
+    ]
+  );
+}
+
+#[test]
+fn force_straggler_report() {
+  let logger = captured_logs();
+  let workunit_store = WorkunitStore::new(false);
+  let _workunit = start_straggler(&workunit_store, "Running: forced straggler");
+
+  let deadline = Instant::now() + Duration::from_secs(600);
+  let mut display = logging_display(Duration::from_secs(0), Duration::from_secs(30));
+  *straggler_deadline(&mut display) = Some(deadline);
+  let has_report = || logger.any(|line| line.contains("Running: forced straggler"));
+
+  // Not forced, and not yet due.
+  display.render(&workunit_store, false);
+  assert!(!has_report());
+
+  display.render(&workunit_store, true);
+  assert!(has_report());
+  assert!(straggler_deadline(&mut display).unwrap() < deadline);
+}
+
+#[test]
+fn completed() {
+  let completed = handle("completed", false);
+  completed.mark_completed();
+  assert!(completed.is_completed());
+  assert!(!completed.cancelled.poll_triggered());
+
+  let cancelled = handle("cancelled", false);
+  cancelled.cancel();
+  assert!(!cancelled.is_completed());
+  assert!(cancelled.cancelled.poll_triggered());
+}
+
+#[tokio::test]
+async fn session_for_run_id_registration() {
+  let (_signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, None);
+  // The number of handles registered for each RunId.
+  let registered = || {
+    let mut registered = sessions
+      .sessions_by_run_id
+      .lock()
+      .iter()
+      .map(|(run_id, (handles, _))| (*run_id, handles.len()))
+      .collect::<Vec<_>>();
+    registered.sort_by_key(|(run_id, _)| run_id.0);
+    registered
+  };
+  let root = Arc::new(handle("root", false));
+  let clone = Arc::new(handle("clone", true));
+
+  // A clone shares the RunId of its Session, and registering a handle twice is a noop.
+  sessions.register_run_id_weak(None, RunId(1), Arc::downgrade(&root), Weak::new());
+  sessions.register_run_id_weak(None, RunId(1), Arc::downgrade(&clone), Weak::new());
+  sessions.register_run_id_weak(None, RunId(1), Arc::downgrade(&root), Weak::new());
+  assert_eq!(registered(), vec![(RunId(1), 2)]);
+
+  // Looking up a Session whose state is dead fails, but does not modify the registrations.
+  assert!(sessions.session_for_run_id(RunId(1)).is_none());
+  assert_eq!(registered(), vec![(RunId(1), 2)]);
+
+  // When the clone observes a new RunId, both handles move to it.
+  sessions.register_run_id_weak(
+    Some(RunId(1)),
+    RunId(2),
+    Arc::downgrade(&clone),
+    Weak::new(),
+  );
+  assert_eq!(registered(), vec![(RunId(2), 2)]);
+  assert!(sessions.session_for_run_id(RunId(1)).is_none());
+
+  // A forked RunId is registered alongside the current RunId.
+  sessions.register_run_id_weak(None, RunId(3), Arc::downgrade(&root), Weak::new());
+  assert_eq!(registered(), vec![(RunId(2), 2), (RunId(3), 1)]);
+
+  // Pruning removes dead handles, and the entries of dead Sessions.
+  std::mem::drop(clone);
+  assert_eq!(registered(), vec![(RunId(2), 2), (RunId(3), 1)]);
+  sessions.prune();
+  assert!(registered().is_empty());
+}
+
+#[tokio::test]
+async fn scoped_metadata_entry() {
+  let map = RwLock::new(HashMap::new());
+  let key = UserMetadataPyValue::new();
+
+  let result = async {
+    let _entry = ScopedMetadataEntry::insert(&map, key.clone(), 1);
+    assert_eq!(map.read().get(&key), Some(&1));
+    "done"
+  }
+  .await;
+  assert_eq!(result, "done");
+  assert!(map.read().is_empty());
+
+  let panicked = std::panic::AssertUnwindSafe(async {
+    let _entry = ScopedMetadataEntry::insert(&map, key.clone(), 2);
+    assert_eq!(map.read().get(&key), Some(&2));
+    panic!("The future panicked.");
+  })
+  .catch_unwind()
+  .await;
+  assert!(panicked.is_err());
+  assert!(map.read().is_empty());
+}
+
+#[test]
+fn set_build_id_once() {
+  let handle = handle("provisional", false);
+  assert_eq!(handle.build_id(), "provisional");
+
+  handle.set_build_id("final".to_owned()).unwrap();
+  assert_eq!(handle.build_id(), "final");
+
+  let err = handle
+    .set_build_id("another".to_owned())
+    .expect_err("Want Err for a second set_build_id");
+  assert!(
+    err.contains("already been set"),
+    "Bad error message: {}",
+    err
+  );
+  assert_eq!(handle.build_id(), "final");
+}
+
+#[tokio::test]
+async fn display_teardown_completes() {
+  let handle = Arc::new(handle("teardown", false));
+  assert!(!handle.teardown_complete.poll_triggered());
+
+  let waiter = {
+    let handle = handle.clone();
+    tokio::spawn(async move { handle.teardown_complete.triggered().await })
+  };
+  handle.display_teardown().await;
+  tokio::time::timeout(Duration::from_secs(5), waiter)
+    .await
+    .expect("Teardown should have completed.")
+    .unwrap();
+}
+
+#[test]
+fn metrics_delta() {
+  let before = MetricsSnapshot(
+    vec![
+      ("affected_file_count", 3),
+      ("preceding_graph_size", 10),
+      ("resulting_graph_size", 10),
+      ("request_count", 4),
+      ("display_lock_wait_ms", 20),
+    ]
+    .into_iter()
+    .collect(),
+  );
+  let after = MetricsSnapshot(
+    vec![
+      ("affected_file_count", 5),
+      ("preceding_graph_size", 10),
+      ("resulting_graph_size", 17),
+      ("request_count", 6),
+      ("display_lock_wait_ms", 35),
+    ]
+    .into_iter()
+    .collect(),
+  );
+  let expected = vec![
+    ("affected_file_count".to_owned(), 5),
+    ("preceding_graph_size".to_owned(), 10),
+    ("resulting_graph_size".to_owned(), 7),
+    ("request_count".to_owned(), 2),
+    ("display_lock_wait_ms".to_owned(), 15),
+  ]
+  .into_iter()
+  .collect::<HashMap<_, _>>();
+  assert_eq!(before.delta(&after), expected);
+}
+
+#[tokio::test]
+async fn signal_during_shutdown_cancels_all_sessions() {
+  let (signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Arc::new(Sessions::with_signal_stream(
+    &Executor::new(),
+    signal_receiver,
+    None,
+  ));
+  let foreground = Arc::new(handle("shutdown_foreground", false));
+  let background = Arc::new(handle("shutdown_background", true));
+  sessions.add(&foreground).unwrap();
+  sessions.add(&background).unwrap();
+
+  let shutdown = {
+    let sessions = sessions.clone();
+    tokio::spawn(async move { sessions.shutdown(Duration::from_secs(30)).await })
+  };
+  // Wait for the shutdown to begin before interrupting it.
+  while sessions.sessions.lock().is_some() {
+    tokio::time::sleep(Duration::from_millis(10)).await;
+  }
+
+  signal_sender.send(()).unwrap();
+  tokio::time::timeout(Duration::from_secs(5), shutdown)
+    .await
+    .expect("The interrupt should have allowed the shutdown to complete.")
+    .unwrap()
+    .unwrap();
+  assert!(foreground.cancelled.poll_triggered());
+  assert!(background.cancelled.poll_triggered());
+  // Once the shutdown has completed, the Sessions that it was waiting for are released.
+  assert!(sessions.shutting_down_sessions.lock().is_empty());
+}
+
+#[tokio::test]
+async fn shutdown_timeout_releases_sessions() {
+  let (_signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, None);
+  let handle = Arc::new(handle("shutdown_timeout", false));
+  sessions.add(&handle).unwrap();
+
+  assert!(sessions.shutdown(Duration::from_millis(10)).await.is_err());
+  assert!(sessions.shutting_down_sessions.lock().is_empty());
+}
+
+#[test]
+fn cancel_guard() {
+  let armed = Arc::new(handle("armed", false));
+  std::mem::drop(CancelGuard(Some(armed.clone())));
+  assert!(armed.cancelled.poll_triggered());
+
+  let disarmed = Arc::new(handle("disarmed", false));
+  CancelGuard(Some(disarmed.clone())).disarm();
+  assert!(!disarmed.cancelled.poll_triggered());
+}
+
+#[test]
+fn run_id_callbacks() {
+  let callbacks = RunIdCallbacks::default();
+  let observed = Arc::new(Mutex::new(Vec::new()));
+  {
+    let observed = observed.clone();
+    callbacks.register(Arc::new(move |run_id| observed.lock().push(run_id)));
+  }
+
+  for run_id in [RunId(1), RunId(2), RunId(3)] {
+    callbacks.notify(run_id);
+  }
+  assert_eq!(*observed.lock(), vec![RunId(1), RunId(2), RunId(3)]);
+}
+
+///
+/// A metadata value which records, when it is dropped, whether the map that contained it was
+/// unlocked at the time.
+///
+struct DropProbe {
+  map: Weak<RwLock<HashMap<UserMetadataPyValue, DropProbe>>>,
+  dropped_unlocked: Arc<Mutex<Vec<bool>>>,
+}
+
+impl Drop for DropProbe {
+  fn drop(&mut self) {
+    if let Some(map) = self.map.upgrade() {
+      self.dropped_unlocked.lock().push(map.try_write().is_some());
+    }
+  }
+}
+
+#[test]
+fn scoped_metadata_entry_drops_values_outside_lock() {
+  let map = Arc::new(RwLock::new(HashMap::new()));
+  let dropped_unlocked = Arc::new(Mutex::new(Vec::new()));
+  let probe = || DropProbe {
+    map: Arc::downgrade(&map),
+    dropped_unlocked: dropped_unlocked.clone(),
+  };
+  let key = UserMetadataPyValue::new();
+
+  let first = ScopedMetadataEntry::insert(&map, key.clone(), probe());
+  // Replacing the value drops the first probe.
+  let second = ScopedMetadataEntry::insert(&map, key.clone(), probe());
+  assert_eq!(*dropped_unlocked.lock(), vec![true]);
+  // Removing the value drops the second probe.
+  std::mem::drop(second);
+  assert_eq!(*dropped_unlocked.lock(), vec![true, true]);
+  std::mem::drop(first);
+  assert!(map.read().is_empty());
+  assert_eq!(*dropped_unlocked.lock(), vec![true, true]);
+}
+
+#[test]
+fn cancellation_latch() {
+  let handle = handle("latched", false);
+  handle.cancellation_latch().trigger();
+  assert!(handle.cancellation_token().is_cancelled());
+}
+
+#[tokio::test]
+async fn run_bounded_completes() {
+  let handle = handle("bounded", false);
+  assert_eq!(
+    handle
+      .run_bounded(Duration::from_secs(5), async { 42 })
+      .await,
+    Ok(42)
+  );
+  // A zero timeout is no timeout.
+  assert_eq!(
+    handle
+      .run_bounded(Duration::ZERO, async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        42
+      })
+      .await,
+    Ok(42)
+  );
+}
+
+#[tokio::test]
+async fn run_bounded_cancelled() {
+  let handle = handle("bounded", false);
+  handle.cancel();
+  assert_eq!(
+    handle
+      .run_bounded(Duration::ZERO, futures::future::pending::<()>())
+      .await,
+    Err(BoundError::Cancelled)
+  );
+}
+
+#[tokio::test]
+async fn run_bounded_timed_out() {
+  let handle = handle("bounded", false);
+  assert_eq!(
+    handle
+      .run_bounded(Duration::from_millis(50), futures::future::pending::<()>())
+      .await,
+    Err(BoundError::TimedOut)
+  );
+}
+
+#[test]
+fn cancel_children() {
+  let parent = handle("parent", false);
+  let children = vec![
+    Arc::new(handle("child_1", true)),
+    Arc::new(handle("child_2", true)),
+  ];
+  for child in &children {
+    parent.add_child(child);
+  }
+
+  parent.cancel_children();
+  for child in &children {
+    assert!(child.cancelled.poll_triggered());
+  }
+  assert!(!parent.cancelled.poll_triggered());
+}
+
+#[tokio::test]
+async fn display_lock_wait() {
+  let handle = Arc::new(handle("display_lock_wait", false));
+  assert_eq!(handle.display_lock_wait(), Duration::ZERO);
+
+  let display = handle.lock_display().await;
+  let waiter = {
+    let handle = handle.clone();
+    Executor::new().spawn(async move {
+      let _display = handle.lock_display().await;
+    })
+  };
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  std::mem::drop(display);
+  waiter.await;
+
+  assert!(
+    handle.display_lock_wait() >= Duration::from_millis(50),
+    "Too little wait time recorded: {:?}",
+    handle.display_lock_wait()
+  );
+}
+
+#[tokio::test]
+async fn display_mode() {
+  let logging = handle("logging", false);
+  assert_eq!(
+    logging.display_mode(),
+    SessionDisplayMode::Logging { armed: false }
+  );
+  logging
+    .lock_display()
+    .await
+    .initialize(&Executor::new())
+    .unwrap();
+  assert_eq!(
+    logging.display_mode(),
+    SessionDisplayMode::Logging { armed: true }
+  );
+
+  // While the display is locked, the mode is unknown.
+  let _display = logging.display.lock().await;
+  assert_eq!(logging.display_mode(), SessionDisplayMode::Unknown);
+
+  let console_ui = handle_with_display(
+    "console_ui",
+    false,
+    SessionDisplay::ConsoleUI(ConsoleUI::new(WorkunitStore::new(false), 1)),
+  );
+  assert_eq!(console_ui.display_mode(), SessionDisplayMode::ConsoleUI);
+}
+
+#[tokio::test]
+async fn spawn_tracked_aborted_on_cancel() {
+  let handle = handle("spawn_tracked", false);
+  let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+  let _abort_handle = handle.spawn_tracked(&Executor::new(), async move {
+    // Holds the sender until the task is aborted.
+    let _sender = sender;
+    futures::future::pending::<()>().await;
+  });
+
+  handle.cancel();
+  tokio::time::timeout(Duration::from_secs(5), receiver)
+    .await
+    .expect("The task should have been aborted promptly.")
+    .expect_err("The task should not have sent a value.");
+}
+
+#[tokio::test]
+async fn time_until_deadline() {
+  let handle = handle("deadline", false);
+  assert_eq!(handle.time_until_deadline(), None);
+
+  handle.set_deadline(&Executor::new(), Duration::from_secs(10));
+  let remaining = handle.time_until_deadline().unwrap();
+  assert!(
+    remaining <= Duration::from_secs(10) && remaining > Duration::from_secs(9),
+    "Unexpected remaining time: {:?}",
+    remaining
+  );
+  assert!(!handle.cancelled.poll_triggered());
+}
+
+#[tokio::test]
+async fn deadline_cancels() {
+  let handle = handle("deadline", false);
+  handle.set_deadline(&Executor::new(), Duration::from_millis(50));
+  tokio::time::timeout(Duration::from_secs(5), handle.cancelled.triggered())
+    .await
+    .expect("The Session should have been cancelled at its deadline.");
+  assert_eq!(handle.time_until_deadline(), Some(Duration::ZERO));
+}
+
+#[tokio::test]
+async fn extend_deadline() {
+  let handle = handle("deadline", false);
+  handle
+    .extend_deadline(Duration::from_secs(1))
+    .expect_err("Want Err without a deadline.");
+
+  handle.set_deadline(&Executor::new(), Duration::from_millis(100));
+  handle.extend_deadline(Duration::from_millis(200)).unwrap();
+
+  // The Session survives past its original deadline...
+  tokio::time::sleep(Duration::from_millis(150)).await;
+  assert!(!handle.cancelled.poll_triggered());
+
+  // ...but is cancelled at the extended deadline.
+  tokio::time::timeout(Duration::from_secs(5), handle.cancelled.triggered())
+    .await
+    .expect("The Session should have been cancelled at its extended deadline.");
+  handle
+    .extend_deadline(Duration::from_secs(1))
+    .expect_err("Want Err once cancelled.");
+}
+
+#[tokio::test]
+async fn drain_and_shutdown_without_cancellation() {
+  let (_signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Arc::new(Sessions::with_signal_stream(
+    &Executor::new(),
+    signal_receiver,
+    None,
+  ));
+  let handle = Arc::new(handle("draining", false));
+  sessions.add(&handle).unwrap();
+
+  // In-flight work which completes within the drain window, recording whether it was cancelled.
+  let work = handle.track_work();
+  let cancelled = handle.cancelled.clone();
+  let finished = Executor::new().spawn(async move {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let was_cancelled = cancelled.poll_triggered();
+    std::mem::drop(work);
+    was_cancelled
+  });
+
+  let shutdown = {
+    let sessions = sessions.clone();
+    tokio::spawn(async move {
+      sessions
+        .drain_and_shutdown(Duration::from_secs(5), Duration::from_secs(5))
+        .await
+    })
+  };
+
+  assert!(!finished.await, "The work should not have been cancelled.");
+  assert!(handle.is_draining());
+  // Dropping the (completed) Session allows the shutdown to complete.
+  std::mem::drop(handle);
+  tokio::time::timeout(Duration::from_secs(5), shutdown)
+    .await
+    .expect("The shutdown should have completed.")
+    .unwrap()
+    .unwrap();
+}
+
+#[tokio::test]
+async fn track_request_while_draining() {
+  let handle = Arc::new(handle("track_request", false));
+  let in_flight = || handle.in_flight.load(std::sync::atomic::Ordering::SeqCst);
+
+  // A request which was tracked before the drain began continues to be tracked.
+  let work = handle.track_request().unwrap();
+  handle.begin_drain();
+  assert_eq!(in_flight(), 1);
+
+  // But a request which begins after the drain is rejected, and is not left tracked.
+  assert_eq!(
+    handle.track_request().err().unwrap(),
+    "Session `track_request` is draining: no new requests may be made."
+  );
+  assert_eq!(in_flight(), 1);
+
+  std::mem::drop(work);
+  tokio::time::timeout(Duration::from_secs(5), handle.wait_for_idle())
+    .await
+    .expect("The Session should have become idle.");
+}
+
+#[tokio::test]
+async fn session_end_callbacks() {
+  let callbacks = Arc::new(SessionEndCallbacks::default());
+  let observed = Arc::new(Mutex::new(Vec::new()));
+  {
+    let observed = observed.clone();
+    callbacks.register(Arc::new(move |summary| observed.lock().push(summary)));
+  }
+
+  let handle = handle("ended", false);
+  {
+    let callbacks = callbacks.clone();
+    handle.on_end(&Executor::new(), move |build_id, completed| {
+      callbacks.notify(SessionSummary {
+        build_id,
+        elapsed: Duration::from_secs(1),
+        run_id: RunId(3),
+        completed,
+        graph_growth: 7,
+      })
+    });
+  }
+  handle.set_build_id("ended_final".to_owned()).unwrap();
+  handle.mark_completed();
+  assert!(observed.lock().is_empty());
+
+  // Dropping the handle ends it.
+  std::mem::drop(handle);
+  tokio::time::timeout(Duration::from_secs(5), async {
+    while observed.lock().is_empty() {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  })
+  .await
+  .expect("The callback should have been invoked.");
+  assert_eq!(
+    *observed.lock(),
+    vec![SessionSummary {
+      build_id: "ended_final".to_owned(),
+      elapsed: Duration::from_secs(1),
+      run_id: RunId(3),
+      completed: true,
+      graph_growth: 7,
+    }]
+  );
+}
+
+// A RequestState with string roots and root metadata, and integer workunit metadata values and
+// observations.
+type TestRequestState = RequestState<&'static str, u32, &'static str, u32>;
+
+fn request_state() -> TestRequestState {
+  RequestState::new(RunId(1))
+}
+
+#[test]
+fn force_new_run_id() {
+  let state = request_state();
+  assert_eq!(
+    state.force_new_run_id(|| panic!("Should not generate a RunId.")),
+    None
+  );
+  assert_eq!(state.run_id(), RunId(1));
+
+  // While forced to be uncacheable, each request observes a fresh RunId.
+  state
+    .force_uncacheable
+    .store(true, std::sync::atomic::Ordering::SeqCst);
+  assert_eq!(
+    state.force_new_run_id(|| RunId(2)),
+    Some((RunId(1), RunId(2)))
+  );
+  assert_eq!(
+    state.force_new_run_id(|| RunId(3)),
+    Some((RunId(2), RunId(3)))
+  );
+  assert_eq!(state.run_id(), RunId(3));
+
+  state
+    .force_uncacheable
+    .store(false, std::sync::atomic::Ordering::SeqCst);
+  assert_eq!(
+    state.force_new_run_id(|| panic!("Should not generate a RunId.")),
+    None
+  );
+  assert_eq!(state.run_id(), RunId(3));
+}
+
+#[test]
+fn record_request_counts_batches() {
+  let state = request_state();
+  assert_eq!(state.request_count(), 0);
+
+  // An empty batch is not a request.
+  assert!(!state.record_request(vec![]));
+  assert_eq!(state.request_count(), 0);
+
+  assert!(state.record_request(vec![("a", None), ("b", None)]));
+  assert_eq!(state.request_count(), 1);
+
+  // Repeated requests for the same roots are each counted, but only the first is the first.
+  assert!(!state.record_request(vec![("a", None)]));
+  assert!(!state.record_request(vec![("a", None)]));
+  assert_eq!(state.request_count(), 3);
+  assert_eq!(state.roots.lock().len(), 2);
+}
+
+#[test]
+fn request_state_reset() {
+  let state = request_state();
+  state
+    .force_uncacheable
+    .store(true, std::sync::atomic::Ordering::SeqCst);
+  assert!(state.record_request(vec![("a", None)]));
+  let key = UserMetadataPyValue::new();
+  state.workunit_metadata_map.write().insert(key.clone(), 7);
+  state
+    .labels
+    .write()
+    .insert("team".to_owned(), "infra".to_owned());
+
+  // The metadata is returned rather than dropped, so that it can be dropped outside of the lock.
+  let metadata = state.reset();
+  assert_eq!(metadata.get(&key), Some(&7));
+  assert!(state.workunit_metadata_map.read().is_empty());
+  assert!(state.roots.lock().is_empty());
+  assert!(state.labels.read().is_empty());
+  assert_eq!(state.request_count(), 0);
+
+  // The next request is again the first, but the RunId and flags are preserved.
+  assert!(state.record_request(vec![("b", None)]));
+  assert_eq!(state.run_id(), RunId(1));
+  assert!(state
+    .force_uncacheable
+    .load(std::sync::atomic::Ordering::SeqCst));
+}
+
+#[test]
+fn last_observed() {
+  let state = request_state();
+  assert_eq!(state.last_observed(&"a"), None);
+
+  state.record_request(vec![("a", Some(3)), ("b", None)]);
+  assert_eq!(state.last_observed(&"a"), Some(3));
+  assert_eq!(state.last_observed(&"b"), None);
+  assert_eq!(state.last_observed(&"c"), None);
+
+  // The single-root lookup matches the slice-based lookup.
+  for root in ["a", "b", "c"] {
+    assert_eq!(
+      state.zip_last_observed(&[root]),
+      vec![(root, state.last_observed(&root))]
+    );
+  }
+}
+
+#[tokio::test]
+async fn poll_records_observations() {
+  let state = request_state();
+
+  // Each poll observes the result of the previous successful poll.
+  let polled = state
+    .poll("a", |last_observed| async move {
+      assert_eq!(last_observed, None);
+      Ok::<_, String>(("first", 1))
+    })
+    .await;
+  assert_eq!(polled, Ok(("first", 1, true)));
+  let polled = state
+    .poll("a", |last_observed| async move {
+      assert_eq!(last_observed, Some(1));
+      Ok::<_, String>(("second", 2))
+    })
+    .await;
+  assert_eq!(polled, Ok(("second", 2, false)));
+
+  // A failed poll does not replace the observation.
+  let polled = state
+    .poll("a", |last_observed| async move {
+      assert_eq!(last_observed, Some(2));
+      Err::<(&str, u32), _>("failed".to_owned())
+    })
+    .await;
+  assert_eq!(polled, Err("failed".to_owned()));
+  assert_eq!(state.last_observed(&"a"), Some(2));
+  assert_eq!(state.request_count(), 2);
+}
+
+#[test]
+fn root_metadata_cleared() {
+  let state = request_state();
+  state.set_root_metadata("a", "experiment");
+  assert_eq!(state.root_metadata(&"a"), Some("experiment"));
+  assert_eq!(state.root_metadata(&"b"), None);
+
+  // Starting a new run clears the metadata.
+  assert_eq!(state.start_run(RunId(2)), RunId(1));
+  assert_eq!(state.root_metadata(&"a"), None);
+
+  // As does a reset.
+  state.set_root_metadata("a", "experiment");
+  state.reset();
+  assert_eq!(state.root_metadata(&"a"), None);
+}
+
+#[tokio::test]
+async fn initial_run_id() {
+  let (_signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, None);
+
+  // As in `Session::new`: the initial RunId is the one generated for the new RequestState.
+  let state: TestRequestState = RequestState::new(sessions.generate_run_id());
+  let run_id = state.run_id();
+  assert_eq!(state.run_id(), run_id);
+
+  // And it is unique to the RequestState, and is only changed by starting a new run.
+  let other: TestRequestState = RequestState::new(sessions.generate_run_id());
+  assert_ne!(other.run_id(), run_id);
+  assert_eq!(state.start_run(sessions.generate_run_id()), run_id);
+  assert_ne!(state.run_id(), run_id);
+}
+
+#[test]
+fn session_values_snapshot_restore() {
+  let values = SessionValues::new(vec!["a"]);
+  let snapshot = values.get();
+
+  // Restoring a snapshot undoes changes made after it was taken.
+  values.restore(vec!["a", "b"]);
+  assert_eq!(values.get(), vec!["a", "b"]);
+  assert_eq!(values.restore(snapshot), vec!["a", "b"]);
+  assert_eq!(values.get(), vec!["a"]);
+}
+
+#[test]
+fn iteration_elapsed_restarts() {
+  let state = request_state();
+  std::thread::sleep(Duration::from_millis(50));
+  assert!(state.iteration_elapsed() >= Duration::from_millis(50));
+
+  // Starting a new run (including via a reset of the Session) restarts the iteration timer.
+  state.start_run(RunId(2));
+  assert!(state.iteration_elapsed() < Duration::from_millis(50));
+}
+
+#[tokio::test]
+async fn fork_run_id() {
+  let (_signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, None);
+  let root = Arc::new(handle("root", false));
+  let run_id = sessions.generate_run_id();
+  sessions.register_run_id_weak(None, run_id, Arc::downgrade(&root), Weak::new());
+
+  // A forked RunId is distinct, and resolves to the forking Session without replacing its
+  // current RunId.
+  let forked = sessions.fork_run_id(Arc::downgrade(&root), Weak::new());
+  assert_ne!(forked, run_id);
+  for run_id in [run_id, forked] {
+    let (handle, _) = sessions.handle_for_run_id(run_id).unwrap();
+    assert!(Arc::ptr_eq(&handle, &root));
+  }
+
+  // But because the state of the forking Session is dead, no Session is returned for it.
+  assert!(sessions.session_for_run_id(forked).is_none());
+}
+
+#[tokio::test]
+async fn isolated_scope() {
+  let (_signal_sender, signal_receiver) = mpsc::unbounded_channel();
+  let sessions = Sessions::with_signal_stream(&Executor::new(), signal_receiver, None);
+  let parent = Arc::new(handle("parent", false));
+  sessions.add(&parent).unwrap();
+
+  // As in `Session::with_isolated_scope`: an isolated child is registered for the scope.
+  let child = Arc::new(handle("child", true));
+  sessions.add(&child).unwrap();
+  parent.add_child(&child);
+  let result = child
+    .run_scoped(async {
+      assert_eq!(sessions.live_count(), 2);
+      assert!(!child.cancelled.poll_triggered());
+      // Cancelling work within the scope does not cancel the parent.
+      child.cancel();
+      "result"
+    })
+    .await;
+  assert_eq!(result, "result");
+  assert!(!parent.cancelled.poll_triggered());
+  std::mem::drop(child);
+  assert_eq!(sessions.live_count(), 1);
+
+  // Once the scope has exited, the child is cancelled even if it was retained, and the parent is
+  // unaffected.
+  let child = Arc::new(handle("retained", true));
+  sessions.add(&child).unwrap();
+  parent.add_child(&child);
+  child.run_scoped(async {}).await;
+  assert!(child.cancelled.poll_triggered());
+  assert!(!parent.cancelled.poll_triggered());
+
+  // And once dropped, it no longer counts as live.
+  std::mem::drop(child);
+  assert_eq!(sessions.live_count(), 1);
+}
+
+#[test]
+fn root_descriptions() {
+  let state = request_state();
+  let describe = |root: &&str| format!("{} for {}", root.to_uppercase(), root);
+  assert!(state.root_descriptions(describe).is_empty());
+
+  state.record_request(vec![("b", None), ("a", Some(1))]);
+  assert_eq!(
+    state.root_descriptions(describe),
+    vec!["A for a".to_owned(), "B for b".to_owned()]
+  );
+
+  // Describing a root may itself inspect the RequestState.
+  assert_eq!(
+    state.root_descriptions(|root| format!("{:?}", state.last_observed(root))),
+    vec!["None".to_owned(), "Some(1)".to_owned()]
+  );
+
+  state.reset();
+  assert!(state.root_descriptions(describe).is_empty());
+}
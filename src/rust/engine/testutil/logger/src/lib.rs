@@ -0,0 +1,86 @@
+// Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+#![deny(warnings)]
+// Enable all clippy lints except for many of the pedantic ones. It's a shame this needs to be copied and pasted across crates, but there doesn't appear to be a way to include inner attributes from a common source.
+#![deny(
+  clippy::all,
+  clippy::default_trait_access,
+  clippy::expl_impl_clone_on_copy,
+  clippy::if_not_else,
+  clippy::needless_continue,
+  clippy::unseparated_literal_suffix,
+  clippy::used_underscore_binding
+)]
+// It is often more clear to show that nothing is being moved.
+#![allow(clippy::match_ref_pats)]
+// Subjective style.
+#![allow(
+  clippy::len_without_is_empty,
+  clippy::redundant_field_names,
+  clippy::too_many_arguments
+)]
+// Default isn't as big a deal as people seem to think it is.
+#![allow(clippy::new_without_default, clippy::new_ret_no_self)]
+// Arc<Mutex> can be more clear than needing to grok Orderings:
+#![allow(clippy::mutex_atomic)]
+
+use parking_lot::Mutex;
+
+///
+/// A logger which captures messages, so that tests can assert on what was logged.
+///
+/// NB: Only one logger may be installed per process, so all tests in a binary share the captured
+/// messages: assertions should match messages which are unique to the test.
+///
+pub struct CapturingLogger(Mutex<Vec<String>>);
+
+impl CapturingLogger {
+  ///
+  /// Returns true if exactly the given message has been logged.
+  ///
+  pub fn contains(&self, message: &str) -> bool {
+    self.any(|line| line == message)
+  }
+
+  ///
+  /// Returns true if any logged message matches the given predicate.
+  ///
+  pub fn any(&self, predicate: impl Fn(&str) -> bool) -> bool {
+    self.0.lock().iter().any(|line| predicate(line))
+  }
+
+  ///
+  /// Returns a copy of all messages logged so far.
+  ///
+  pub fn lines(&self) -> Vec<String> {
+    self.0.lock().clone()
+  }
+}
+
+impl log::Log for CapturingLogger {
+  fn enabled(&self, metadata: &log::Metadata) -> bool {
+    metadata.level() <= log::Level::Debug
+  }
+
+  fn log(&self, record: &log::Record) {
+    if self.enabled(record.metadata()) {
+      self.0.lock().push(format!("{}", record.args()));
+    }
+  }
+
+  fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger(parking_lot::const_mutex(Vec::new()));
+
+///
+/// Installs the CapturingLogger (at `Debug` level) if it has not already been installed, and
+/// returns it.
+///
+pub fn captured_logs() -> &'static CapturingLogger {
+  if log::set_logger(&LOGGER).is_ok() {
+    log::set_max_level(log::LevelFilter::Debug);
+  }
+  &LOGGER
+}
@@ -359,3 +359,13 @@ impl From<TestDirectory> for TestTree {
     TestTree { tree }
   }
 }
+
+///
+/// Creates `count` distinct digests (of the decimal representations of `0..count`), for tests and
+/// benchmarks of batch digest conversions.
+///
+pub fn proto_digests(count: usize) -> Vec<remexec::Digest> {
+  (0..count)
+    .map(|i| hashing::Digest::of_bytes(format!("{}", i).as_bytes()).into())
+    .collect()
+}
@@ -53,7 +53,12 @@ mod metrics;
 /// A unique id for a single run or `--loop` iteration of Pants within a single Scheduler.
 ///
 /// RunIds are not comparable across Scheduler instances, and only equality is meaningful, not
-/// ordering.
+/// ordering. Consequently, RunId intentionally does not implement `PartialOrd` or `Ord`:
+///
+/// ```compile_fail
+/// use workunit_store::RunId;
+/// assert!(RunId(0) < RunId(1));
+/// ```
 ///
 /// NB: This type is defined here to make it easily accessible to both the `process_execution`
 /// and `engine` crates: it's not actually used by the WorkunitStore.
@@ -61,6 +66,15 @@ mod metrics;
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct RunId(pub u32);
 
+impl RunId {
+  ///
+  /// Returns true if this RunId refers to the same run as the given RunId.
+  ///
+  pub fn is_same(&self, other: &RunId) -> bool {
+    self == other
+  }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Ord, PartialOrd)]
 pub struct SpanId(u64);
 
@@ -147,6 +161,18 @@ impl Workunit {
   }
 }
 
+///
+/// A running Workunit and its running children, as returned by `WorkunitStore::running_tree`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WorkunitTreeNode {
+  pub span_id: SpanId,
+  pub name: String,
+  pub desc: Option<String>,
+  pub blocked: bool,
+  pub children: Vec<WorkunitTreeNode>,
+}
+
 #[derive(Clone, Debug)]
 pub enum WorkunitState {
   Started {
@@ -235,7 +261,7 @@ enum StoreMsg {
 
 #[derive(Clone)]
 pub struct WorkunitStore {
-  log_starting_workunits: bool,
+  log_starting_workunits: Arc<AtomicBool>,
   streaming_workunit_data: StreamingWorkunitData,
   heavy_hitters_data: HeavyHittersData,
   observation_data: ObservationsData,
@@ -516,6 +542,38 @@ impl HeavyHittersData {
     stragglers
   }
 
+  fn running_tree(&self) -> Vec<WorkunitTreeNode> {
+    self.refresh_store();
+    let inner = self.inner.lock();
+
+    fn node_for(inner: &HeavyHittersInnerStore, entry: NodeIndex<u32>) -> Option<WorkunitTreeNode> {
+      let span_id = inner.running_graph[entry];
+      let workunit = inner.workunit_records.get(&span_id)?;
+      let mut children = inner
+        .running_graph
+        .neighbors_directed(entry, petgraph::Direction::Outgoing)
+        .filter_map(|child| node_for(inner, child))
+        .collect::<Vec<_>>();
+      children.sort_by_key(|child| child.span_id);
+      Some(WorkunitTreeNode {
+        span_id,
+        name: workunit.name.clone(),
+        desc: workunit.metadata.desc.clone(),
+        blocked: workunit.state.blocked(),
+        children,
+      })
+    }
+
+    // Workunits whose parents have already completed (or were never recorded) are roots.
+    let mut roots = inner
+      .running_graph
+      .externals(petgraph::Direction::Incoming)
+      .filter_map(|entry| node_for(&inner, entry))
+      .collect::<Vec<_>>();
+    roots.sort_by_key(|root| root.span_id);
+    roots
+  }
+
   fn is_visible(workunit: &Workunit) -> bool {
     workunit.metadata.level <= Level::Debug
       && workunit.metadata.desc.is_some()
@@ -571,7 +629,7 @@ fn first_matched_parent(
 impl WorkunitStore {
   pub fn new(log_starting_workunits: bool) -> WorkunitStore {
     WorkunitStore {
-      log_starting_workunits,
+      log_starting_workunits: Arc::new(AtomicBool::new(log_starting_workunits)),
       // TODO: Create one `StreamingWorkunitData` per subscriber, and zero if no subscribers are
       // installed.
       streaming_workunit_data: StreamingWorkunitData::new(),
@@ -587,6 +645,21 @@ impl WorkunitStore {
     }))
   }
 
+  ///
+  /// Enables or disables logging of workunits as they start, for this store and all of its clones.
+  ///
+  /// Workunits which have already started are unaffected.
+  ///
+  pub fn set_log_starting_workunits(&self, enabled: bool) {
+    self
+      .log_starting_workunits
+      .store(enabled, atomic::Ordering::SeqCst);
+  }
+
+  pub fn log_starting_workunits(&self) -> bool {
+    self.log_starting_workunits.load(atomic::Ordering::SeqCst)
+  }
+
   ///
   /// Return visible workunits which have been running longer than the duration_threshold, sorted
   /// in ascending order by their duration.
@@ -603,6 +676,13 @@ impl WorkunitStore {
     self.heavy_hitters_data.heavy_hitters(k)
   }
 
+  ///
+  /// Return all running workunits as a forest, ordered by SpanId at each level.
+  ///
+  pub fn running_tree(&self) -> Vec<WorkunitTreeNode> {
+    self.heavy_hitters_data.running_tree()
+  }
+
   fn start_workunit(
     &self,
     span_id: SpanId,
@@ -635,7 +715,7 @@ impl WorkunitStore {
       .send(StoreMsg::Started(started.clone()))
       .unwrap();
 
-    if self.log_starting_workunits {
+    if self.log_starting_workunits() {
       started.log_workunit_state(false)
     }
     started
@@ -2,7 +2,9 @@ use std::collections::HashSet;
 use std::sync::atomic;
 use std::time::Duration;
 
-use crate::{SpanId, WorkunitMetadata, WorkunitState, WorkunitStore};
+use logger::captured_logs;
+
+use crate::{RunId, SpanId, WorkunitMetadata, WorkunitState, WorkunitStore, WorkunitTreeNode};
 
 #[test]
 fn heavy_hitters_basic() {
@@ -56,6 +58,73 @@ fn straggling_workunits_blocked_path() {
   assert!(ws.straggling_workunits(Duration::from_secs(0)).is_empty());
 }
 
+#[test]
+fn running_tree() {
+  // The completed grandchild is omitted, while the blocked child is included.
+  let ws = create_store(vec![wu_root(0), wu(2, 0)], vec![wu(1, 0)], vec![wu(3, 1)]);
+  let node = |span_id: u64, blocked: bool, children: Vec<WorkunitTreeNode>| WorkunitTreeNode {
+    span_id: SpanId(span_id),
+    name: format!("{}", span_id),
+    desc: Some(format!("{}", span_id)),
+    blocked,
+    children,
+  };
+  assert_eq!(
+    ws.running_tree(),
+    vec![node(
+      0,
+      false,
+      vec![node(1, true, vec![]), node(2, false, vec![])]
+    )]
+  );
+}
+
+#[test]
+fn log_starting_workunits_toggle() {
+  let logs = captured_logs();
+  let ws = create_store(vec![wu_root(0), wu(1, 0)], vec![], vec![]);
+  assert!(ws.log_starting_workunits());
+
+  // Toggling via a clone affects the original, and does not discard already-started workunits.
+  ws.clone().set_log_starting_workunits(false);
+  assert!(!ws.log_starting_workunits());
+  assert_eq!(
+    vec!["1"],
+    ws.straggling_workunits(Duration::from_secs(0))
+      .into_iter()
+      .map(|(_, n)| n)
+      .collect::<Vec<_>>()
+  );
+
+  // Workunits started while disabled are recorded, but their start is not logged.
+  let (span_id, parent_id, metadata) = wu_desc(2, 0, "log_starting_disabled");
+  ws.start_workunit(span_id, "2".to_owned(), parent_id, metadata);
+  assert!(!logs.contains("Starting: log_starting_disabled"));
+  assert_eq!(
+    vec!["1", "log_starting_disabled"]
+      .into_iter()
+      .map(|n| n.to_owned())
+      .collect::<HashSet<_>>(),
+    ws.straggling_workunits(Duration::from_secs(0))
+      .into_iter()
+      .map(|(_, n)| n)
+      .collect::<HashSet<_>>()
+  );
+
+  // And once re-enabled, starts are logged again.
+  ws.set_log_starting_workunits(true);
+  assert!(ws.log_starting_workunits());
+  let (span_id, parent_id, metadata) = wu_desc(3, 0, "log_starting_enabled");
+  ws.start_workunit(span_id, "3".to_owned(), parent_id, metadata);
+  assert!(logs.contains("Starting: log_starting_enabled"));
+}
+
+#[test]
+fn run_id_is_same() {
+  assert!(RunId(1).is_same(&RunId(1)));
+  assert!(!RunId(1).is_same(&RunId(2)));
+}
+
 #[test]
 fn workunit_span_id_has_16_digits_len_hex_format() {
   let number: u64 = 1;
@@ -141,6 +210,12 @@ fn wu(span_id: u64, parent_id: u64) -> AnonymousWorkunit {
   wu_meta(span_id, Some(parent_id), WorkunitMetadata::default())
 }
 
+fn wu_desc(span_id: u64, parent_id: u64, desc: &str) -> AnonymousWorkunit {
+  let mut workunit = wu(span_id, parent_id);
+  workunit.2.desc = Some(desc.to_owned());
+  workunit
+}
+
 fn wu_meta(
   span_id: u64,
   parent_id: Option<u64>,